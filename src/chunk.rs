@@ -6,4 +6,117 @@ pub struct Chunk {
     pub shard: String,
     pub min: bson::Document,
     pub max: bson::Document,
+    pub lastmod: Option<bson::Timestamp>,
+    /// epoch of the collection the version belongs to, no longer stored with each chunk from 5.0
+    #[serde(rename = "lastmodEpoch")]
+    pub epoch: Option<bson::oid::ObjectId>,
+}
+
+/// Ownership of a chunk's range as seen by a fresh read of the routing table, relative to a shard being written to
+#[derive(Debug, PartialEq)]
+pub enum Ownership {
+    /// the range is still a single chunk at the same version, owned by the same shard
+    Unchanged,
+    /// the range is still a single chunk owned by a shard other than the one being written to, but its version
+    /// changed (e.g. it was migrated)
+    Moved(String),
+    /// the routing table changed in a way that makes the range (or part of it) live data on the shard being written to,
+    /// or the range no longer maps to a single chunk
+    Changed,
+}
+
+impl Chunk {
+    /// the single range of an unsharded collection, all of it on its database's primary shard
+    pub fn unsharded(primary: String) -> Self {
+        Chunk {
+            shard: primary,
+            min: bson::doc! { "_id": bson::Bson::MinKey },
+            max: bson::doc! { "_id": bson::Bson::MaxKey },
+            lastmod: None,
+            epoch: None,
+        }
+    }
+
+    /// compare this chunk against the chunks currently overlapping its range, from the point of view of a write to
+    /// `target_shard`
+    pub fn ownership(&self, current: &[Chunk], target_shard: &str) -> Ownership {
+        match current {
+            [chunk] if chunk.min == self.min && chunk.max == self.max => {
+                if chunk.shard == target_shard {
+                    Ownership::Changed
+                } else if chunk.shard == self.shard && chunk.lastmod == self.lastmod {
+                    Ownership::Unchanged
+                } else {
+                    Ownership::Moved(chunk.shard.clone())
+                }
+            }
+            _ => Ownership::Changed,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::{doc, Timestamp};
+
+    use super::{Chunk, Ownership};
+
+    fn chunk(shard: &str, min: i32, max: i32, version: u32) -> Chunk {
+        Chunk {
+            shard: String::from(shard),
+            min: doc! { "a": min },
+            max: doc! { "a": max },
+            lastmod: Some(Timestamp {
+                time: version,
+                increment: 0,
+            }),
+            epoch: None,
+        }
+    }
+
+    #[test]
+    fn unchanged_chunk() {
+        let scanned = chunk("shard01", 0, 10, 1);
+        let current = vec![chunk("shard01", 0, 10, 1)];
+        assert_eq!(Ownership::Unchanged, scanned.ownership(&current, "shard02"));
+    }
+
+    #[test]
+    fn chunk_moved_to_another_shard() {
+        let scanned = chunk("shard01", 0, 10, 1);
+        let current = vec![chunk("shard03", 0, 10, 2)];
+        assert_eq!(
+            Ownership::Moved(String::from("shard03")),
+            scanned.ownership(&current, "shard02")
+        );
+    }
+
+    #[test]
+    fn chunk_moved_to_target_shard() {
+        let scanned = chunk("shard01", 0, 10, 1);
+        let current = vec![chunk("shard02", 0, 10, 2)];
+        assert_eq!(Ownership::Changed, scanned.ownership(&current, "shard02"));
+    }
+
+    #[test]
+    fn unsharded_collection_after_move_primary() {
+        let scanned = Chunk::unsharded(String::from("shard01"));
+        let current = vec![Chunk::unsharded(String::from("shard01"))];
+        assert_eq!(Ownership::Unchanged, scanned.ownership(&current, "shard02"));
+        let moved = vec![Chunk::unsharded(String::from("shard03"))];
+        assert_eq!(
+            Ownership::Moved(String::from("shard03")),
+            scanned.ownership(&moved, "shard02")
+        );
+        let to_target = vec![Chunk::unsharded(String::from("shard02"))];
+        assert_eq!(Ownership::Changed, scanned.ownership(&to_target, "shard02"));
+    }
+
+    #[test]
+    fn chunk_split_or_missing() {
+        let scanned = chunk("shard01", 0, 10, 1);
+        let current = vec![chunk("shard01", 0, 5, 2), chunk("shard01", 5, 10, 2)];
+        assert_eq!(Ownership::Changed, scanned.ownership(&current, "shard02"));
+        assert_eq!(Ownership::Changed, scanned.ownership(&[], "shard02"));
+    }
 }
@@ -1,4 +1,11 @@
-use clap::{Parser, Subcommand};
+use std::{ffi::OsString, path::PathBuf};
+
+use clap::{
+    error::ErrorKind, ArgAction, Command, CommandFactory, FromArgMatches, Parser, Subcommand,
+    ValueEnum,
+};
+
+use crate::{host_map, read_preference, shard_config};
 
 /// Simple program to greet a person
 #[derive(Parser)]
@@ -16,28 +23,419 @@ pub struct Args {
     #[arg(short, long, default_value = "test")]
     pub coll: String,
 
+    /// TOML file of option values keyed by long option name (`uri = "..."`, `batch_size = 500`), subcommand options under `[<subcommand>]`, host mappings under `[host_map]` and per-shard credential and TLS overrides under `[shards.<name>]`; flags and ORPHANAGE_<OPTION> environment variables (ORPHANAGE_<SUBCOMMAND>_<OPTION> for subcommand options) take precedence
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+
+    /// Read the routing table and every shard at a common cluster time (requires 5.0+, and only suits collections small enough that every shard read starts within the server's minSnapshotHistoryWindowInSeconds, 300 by default, or later reads fail with SnapshotTooOld)
+    #[arg(long, global = true, default_value_t = false)]
+    pub snapshot: bool,
+
+    /// Seconds between progress lines during scans, 0 to disable
+    #[arg(long, global = true, default_value_t = 10)]
+    pub progress_interval: u64,
+
+    /// Shard members scans read from, one chosen per shard at startup by server selection and chosen again if it cannot be selected later, independent of the router connection's read preference (writes always go to shard primaries) [default: primary]
+    #[arg(long, global = true, value_enum)]
+    pub shard_read_preference: Option<read_preference::Mode>,
+
+    /// Tag set shard reads are restricted to, e.g. `dc:east,use:analytics`, repeat to fall back to later tag sets when no member matches earlier ones
+    #[arg(long = "shard-read-tags", global = true, requires = "shard_read_preference", value_parser = read_preference::parse_tag_set)]
+    pub shard_read_tags: Vec<std::collections::HashMap<String, String>>,
+
+    /// Reach a shard host listed in config.shards at another address, e.g. `shard01-a.internal:27017=localhost:37017` for an SSH tunnel or port-forward; repeatable and taking precedence over the config file's `[host_map]`, remapped shards are connected to directly, preferring their primary
+    #[arg(long, global = true, value_parser = host_map::parse_mapping)]
+    pub host_map: Vec<(String, String)>,
+
+    /// `"internal:port" = "reachable:port"` host mappings from the `[host_map]` table of the config file
+    #[arg(skip)]
+    pub config_host_map: host_map::HostMap,
+
+    /// per-shard overrides from the `[shards.<name>]` tables of the config file
+    #[arg(skip)]
+    pub shard_config: shard_config::ShardConfig,
+
+    /// Seconds between samples of the serverStatus, replSetGetStatus and replSetGetConfig of each shard member scans read from (and of its primary when updating), dispatch to a shard slows down past half of any threshold below and pauses above it, 0 to disable throttling
+    #[arg(long, global = true, default_value_t = 5)]
+    pub health_interval: u64,
+
+    /// Operations queued on a sampled shard member above which dispatch to the shard pauses
+    #[arg(long, global = true, default_value_t = 100)]
+    pub max_queued_ops: u64,
+
+    /// Percentage of a sampled shard member's WiredTiger cache in use above which dispatch to the shard pauses
+    #[arg(long, global = true, default_value_t = 90.0)]
+    pub max_cache_used: f64,
+
+    /// Percentage of a sampled shard member's WiredTiger cache holding dirty data above which dispatch to the shard pauses
+    #[arg(long, global = true, default_value_t = 15.0)]
+    pub max_cache_dirty: f64,
+
+    /// Seconds of replication lag on a shard (of its slowest secondary that is neither delayed nor hidden) above which dispatch to the shard pauses
+    #[arg(long, global = true, default_value_t = 30)]
+    pub max_replication_lag: u64,
+
+    /// Seconds dispatch to a shard may stay paused over a health threshold before the run fails on it
+    #[arg(long, global = true, default_value_t = 600, value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_pause: u64,
+
+    /// Number of chunk ranges scanned (and written, when updating) at once on each shard, a range only starts once its shard's throttle lets it through
+    #[arg(long, global = true, default_value_t = 4, value_parser = clap::value_parser!(u64).range(1..))]
+    pub concurrency: u64,
+
+    /// Write Prometheus metrics for the run to this file when it finishes, e.g. for node_exporter's textfile collector
+    #[arg(long, global = true)]
+    pub metrics_file: Option<std::path::PathBuf>,
+
+    /// Format of log lines, json lines carry the run id, namespace, shard and chunk bounds as fields
+    #[arg(long, global = true, value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+
+    /// Write a json line per span (metadata load, shard connections, chunk range scans, write batches) with its timing when it closes
+    #[arg(long, global = true)]
+    pub trace_file: Option<std::path::PathBuf>,
+
+    /// Export spans to an OTLP/HTTP collector, e.g. http://localhost:4318/v1/traces
+    #[arg(long, global = true)]
+    pub otlp_endpoint: Option<String>,
+
+    /// Do not check the router and shard users' privileges before running
+    #[arg(long, global = true, default_value_t = false)]
+    pub skip_preflight: bool,
+
+    /// Scan shards without a usable index on the shard key (missing, partial, sparse, collated or on other fields) with a collection scan rather than refusing to run [heaviest performance impact]
+    #[arg(long, global = true, default_value_t = false)]
+    pub allow_collscan: bool,
+
+    /// Run the full scan and print which documents on which shards would be written, without writing anything
+    #[arg(long, global = true, default_value_t = false)]
+    pub dry_run: bool,
+
+    /// Number of orphan IDs written per batch by update and unmark
+    #[arg(long, global = true, default_value_t = 1000, value_parser = clap::value_parser!(u64).range(1..))]
+    pub batch_size: u64,
+
+    /// Write concern `w` for each write by update, unmark and recover (a number of nodes, "majority" or a custom tag set), defaults to the server's
+    #[arg(long, global = true)]
+    pub write_concern: Option<String>,
+
+    /// Number of times a write is retried after a transient error
+    #[arg(long, global = true, default_value_t = 3)]
+    pub write_retries: u32,
+
     #[command(subcommand)]
     pub mode: Mode,
 }
 
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
 #[derive(Subcommand)]
 pub enum Mode {
     /// Return each shard's orphan count based on metadata [lowest performance impact]
     Estimate,
+    /// Check that the namespace's chunks cover the whole shard key range without gaps or overlaps, are owned by existing shards and have strictly increasing versions of the epoch in config.collections [lowest performance impact]
+    CheckMetadata,
+    /// Compare the collection's uuid, options and indexes on every shard against config.collections and each other, catching leftovers of dropped and recreated collections [low performance impact]
+    CheckCollection,
     /// Query each shard's real orphan count or list of IDs [heavier performance impact]
     Print {
         /// Set true to print a verbose map of each shard's orphan ID
         #[arg(long, default_value_t = false)]
         verbose: bool,
     },
+    /// Compare each orphan against the document with the same ID on its owning shard, reporting field-level differences [heavier performance impact]
+    Diff {
+        /// Set true to also print the IDs of identical and unique orphans
+        #[arg(long, default_value_t = false)]
+        verbose: bool,
+    },
+    /// Move orphans whose ID does not exist on the owning shard back through the router, removing them from the shard they were found on [heaviest performance impact]
+    Recover {
+        /// copy each orphan to this namespace before moving it
+        #[arg(long = "archive-ns")]
+        archive_ns: Option<String>,
+    },
     /// Query and update each shard, marking its orphans or writing their IDs to a designated namespace [heaviest performance impact]
     Update {
         /// instead of updating in place, write orphan IDs to a new namespace
         #[arg(long = "ns")]
         target_ns: Option<String>,
+        /// Field set on each orphan to mark it
+        #[arg(long, default_value = "orphaned")]
+        field: String,
+        /// Value the marker field is set to, booleans and numbers keep their type
+        #[arg(long, default_value = "true")]
+        value: String,
+        /// Stamp this run id into <field>_meta.run_id on each marked orphan
+        #[arg(long)]
+        run_id: Option<String>,
+        /// Set true to stamp the time the run started into <field>_meta.marked_at
+        #[arg(long, default_value_t = false)]
+        stamp_time: bool,
+        /// Set true to stamp the name of the shard owning the orphan's chunk into <field>_meta.owner
+        #[arg(long, default_value_t = false)]
+        stamp_owner: bool,
     },
+    /// Remove the marker field and run metadata set by update from every shard [heavy performance impact]
+    Unmark {
+        /// Marker field to remove
+        #[arg(long, default_value = "orphaned")]
+        field: String,
+        /// Only unmark documents stamped with this run id
+        #[arg(long)]
+        run_id: Option<String>,
+    },
+}
+
+/// prefix of the environment variable each option can be set from, e.g. ORPHANAGE_URI or ORPHANAGE_UPDATE_FIELD
+const ENV_PREFIX: &str = "ORPHANAGE_";
+
+/// set each option of the command to be read from its environment variable, hiding the values from --help as the uri
+/// may hold a password
+fn with_env(command: Command, prefix: &str) -> Command {
+    let longs = command
+        .get_arguments()
+        .filter_map(|arg| {
+            arg.get_long()
+                .map(|long| (arg.get_id().to_string(), long.to_string()))
+        })
+        .collect::<Vec<_>>();
+    longs.into_iter().fold(command, |command, (id, long)| {
+        let var = format!("{}{}", prefix, long.replace('-', "_").to_uppercase());
+        command.mut_arg(id, |arg| arg.env(var).hide_env_values(true))
+    })
+}
+
+/// the config file given with --config or ORPHANAGE_CONFIG, read before the rest of the arguments as its values become
+/// their defaults
+fn config_path(args: &[OsString]) -> Option<PathBuf> {
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            break;
+        }
+        if arg == "--config" {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.to_str().and_then(|arg| arg.strip_prefix("--config=")) {
+            return Some(PathBuf::from(path));
+        }
+    }
+    std::env::var_os(format!("{}CONFIG", ENV_PREFIX)).map(PathBuf::from)
+}
+
+fn load_config(path: &std::path::Path) -> Result<toml::Table, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|err| format!("cannot read config {}: {}", path.display(), err))?;
+    toml::from_str(&contents)
+        .map_err(|err| format!("cannot parse config {}: {}", path.display(), err))
+}
+
+fn config_value(key: &str, value: &toml::Value) -> Result<String, String> {
+    match value {
+        toml::Value::String(value) => Ok(value.clone()),
+        toml::Value::Integer(value) => Ok(value.to_string()),
+        toml::Value::Float(value) => Ok(value.to_string()),
+        toml::Value::Boolean(value) => Ok(value.to_string()),
+        _ => Err(format!(
+            "config value of {} must be a string, number or boolean",
+            key
+        )),
+    }
+}
+
+/// set the config file's value of a single option as its default
+fn set_default(command: Command, key: &str, value: &toml::Value) -> Result<Command, String> {
+    let long = key.replace('_', "-");
+    let arg = command
+        .get_arguments()
+        .find(|arg| arg.get_long() == Some(long.as_str()) && arg.get_id() != "config")
+        .ok_or_else(|| format!("unknown option {} in config", key))?;
+    let values = match value {
+        toml::Value::Array(values) if matches!(arg.get_action(), ArgAction::Append) => values
+            .iter()
+            .map(|value| config_value(key, value))
+            .collect::<Result<Vec<_>, _>>()?,
+        toml::Value::Array(_) => {
+            return Err(format!("option {} in config takes a single value", key))
+        }
+        value => vec![config_value(key, value)?],
+    };
+    let id = arg.get_id().to_string();
+    // the uri may hold a password, keep it out of --help
+    let hide = id == "uri";
+    Ok(command.mut_arg(id, |arg| {
+        arg.default_values(values).hide_default_value(hide)
+    }))
+}
+
+/// tables of the config file holding connection settings rather than option values
+const CONNECTION_TABLES: &[&str] = &["host_map", "shards"];
+
+/// set the values of a config file as the defaults of the matching options, so flags and environment variables still
+/// take precedence
+fn apply_config(mut command: Command, config: &toml::Table) -> Result<Command, String> {
+    for (key, value) in config {
+        command = match value {
+            toml::Value::Table(_) if CONNECTION_TABLES.contains(&key.as_str()) => command,
+            _ if CONNECTION_TABLES.contains(&key.as_str()) => {
+                return Err(format!("{} in config must be a table", key))
+            }
+            toml::Value::Table(section) => {
+                let mut subcommand = command
+                    .find_subcommand(key)
+                    .cloned()
+                    .ok_or_else(|| format!("unknown subcommand [{}] in config", key))?;
+                for (option, value) in section {
+                    subcommand = set_default(subcommand, option, value)
+                        .map_err(|err| format!("{} of [{}]", err, key))?;
+                }
+                command.mut_subcommand(key, |_| subcommand)
+            }
+            value => set_default(command, key, value)?,
+        };
+    }
+    Ok(command)
 }
 
 pub fn args() -> Args {
-    Args::parse()
+    let argv = std::env::args_os().collect::<Vec<_>>();
+    let mut command = with_env(Args::command(), ENV_PREFIX);
+    let subcommands = command
+        .get_subcommands()
+        .map(|subcommand| subcommand.get_name().to_string())
+        .collect::<Vec<_>>();
+    for name in subcommands {
+        let prefix = format!("{}{}_", ENV_PREFIX, name.to_uppercase());
+        command = command.mut_subcommand(&name, |subcommand| with_env(subcommand, &prefix));
+    }
+    let config = match config_path(&argv).map(|path| load_config(&path)) {
+        Some(Ok(config)) => config,
+        Some(Err(err)) => command.error(ErrorKind::InvalidValue, err).exit(),
+        None => toml::Table::new(),
+    };
+    let configured = apply_config(command.clone(), &config).and_then(|command| {
+        Ok((
+            command,
+            host_map::HostMap::from_config(&config)?,
+            shard_config::ShardConfig::from_config(&config)?,
+        ))
+    });
+    let (command, config_host_map, shard_config) = match configured {
+        Ok(configured) => configured,
+        Err(err) => command.error(ErrorKind::InvalidValue, err).exit(),
+    };
+    let matches = command.get_matches_from(argv);
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    args.config_host_map = config_host_map;
+    args.shard_config = shard_config;
+    args
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::{CommandFactory, FromArgMatches};
+
+    use super::{Args, Mode};
+
+    fn parse(config: &str, argv: &[&str]) -> Args {
+        let config = toml::from_str(config).unwrap();
+        let matches = super::apply_config(Args::command(), &config)
+            .unwrap()
+            .try_get_matches_from(argv)
+            .unwrap();
+        Args::from_arg_matches(&matches).unwrap()
+    }
+
+    const CONFIG: &str = r#"
+        uri = "mongodb://router.internal:27017"
+        db = "app"
+        batch_size = 500
+        dry_run = true
+
+        [update]
+        field = "stale"
+        stamp_owner = true
+
+        [host_map]
+        "shard01-a.internal:27017" = "localhost:37017"
+
+        [shards.shard01]
+        username = "local"
+    "#;
+
+    #[test]
+    fn config_sets_defaults() {
+        let args = parse(CONFIG, &["orphanage", "update"]);
+        assert_eq!("mongodb://router.internal:27017", args.uri);
+        assert_eq!("app", args.db);
+        assert_eq!("test", args.coll);
+        assert_eq!(500, args.batch_size);
+        assert!(args.dry_run);
+        match args.mode {
+            Mode::Update {
+                field, stamp_owner, ..
+            } => {
+                assert_eq!("stale", field);
+                assert!(stamp_owner);
+            }
+            _ => panic!("expected update"),
+        }
+    }
+
+    #[test]
+    fn flags_override_config() {
+        let args = parse(
+            CONFIG,
+            &[
+                "orphanage",
+                "--db",
+                "other",
+                "update",
+                "--batch-size",
+                "10",
+                "--field",
+                "x",
+            ],
+        );
+        assert_eq!("other", args.db);
+        assert_eq!(10, args.batch_size);
+        match args.mode {
+            Mode::Update { field, .. } => assert_eq!("x", field),
+            _ => panic!("expected update"),
+        }
+    }
+
+    #[test]
+    fn reject_unknown_config() {
+        let apply =
+            |config: &str| super::apply_config(Args::command(), &toml::from_str(config).unwrap());
+        assert!(apply("urii = \"mongodb://localhost\"").is_err());
+        assert!(apply("[upgrade]\nfield = \"x\"").is_err());
+        assert!(apply("[update]\nverbose = true").is_err());
+        assert!(apply("db = [\"a\", \"b\"]").is_err());
+        assert!(apply("config = \"other.toml\"").is_err());
+        assert!(apply("host_map = [\"a:1=b:1\"]").is_err());
+    }
+
+    #[test]
+    fn config_path_from_args() {
+        let argv = |args: &[&str]| {
+            args.iter()
+                .map(std::ffi::OsString::from)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            Some(std::path::PathBuf::from("a.toml")),
+            super::config_path(&argv(&["orphanage", "--config", "a.toml", "print"]))
+        );
+        assert_eq!(
+            Some(std::path::PathBuf::from("b.toml")),
+            super::config_path(&argv(&["orphanage", "print", "--config=b.toml"]))
+        );
+    }
 }
@@ -1,23 +1,87 @@
 // TODO: This file contains a lot of sloppy code and needs to be cleaned up
 
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use futures::{future::join_all, TryStreamExt};
-use mongodb::bson;
+use futures::{future::join_all, StreamExt, TryStreamExt};
+use mongodb::{
+    bson,
+    options::{ReadPreference, SelectionCriteria},
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::Instrument;
 
 use crate::{
-    chunk::Chunk,
+    chunk::{Chunk, Ownership},
     db,
-    orphan::{Orphan, OrphanSummary},
-    util, BUFFER_SIZE,
+    diff::DiffReport,
+    health::{self, HealthOptions, Throttle},
+    host_map::HostMap,
+    marker::{self, Marker},
+    metrics::{self, Metrics},
+    orphan::{self, Orphan, OrphanSummary, RecoverySummary, Remnant},
+    plan::{Action, DryRunPlan},
+    progress::Progress,
+    read_preference::{PinnedRead, ShardReadPreference},
+    routing_table,
+    shard_collections::{self, ConfigCollection, ShardCollection},
+    shard_config::ShardConfig,
+    shard_key_index::{self, RangeScan},
+    shutdown::Shutdown,
+    util,
+    write::{self, ShardWriteSummary, WriteOptions, WriteSummary},
+    BUFFER_SIZE,
 };
 
+/// number of orphans compared against their owning shard at once
+const DIFF_CONCURRENCY: usize = 64;
+
+/// shard label of writes sent through the router to whichever shard owns the document, e.g. archive inserts
+const ROUTER: &str = "router";
+
 // pub struct ClusterClient(Standalone, ReplicaSet, Sharded);
 
+/// Options controlling how the cluster is read
+#[derive(Debug, Clone, Default)]
+pub struct ClusterOptions {
+    /// read the routing table and every shard at a common cluster time, where the server supports it
+    pub snapshot: bool,
+    /// stops scans from dispatching new work once triggered
+    pub shutdown: Shutdown,
+    /// how often to log scan progress, never if unset
+    pub progress_interval: Option<Duration>,
+    /// counters recorded during scans and writes, for exposition once the run finishes
+    pub metrics: Arc<Metrics>,
+    /// sample shard health and slow down or pause dispatch to struggling shards, never if unset
+    pub health: Option<HealthOptions>,
+    /// which shard members scans read from, shard primaries if unset
+    pub shard_read_preference: Option<Arc<ShardReadPreference>>,
+    /// reachable addresses for shard hosts that cannot be resolved or reached directly
+    pub host_map: HostMap,
+    /// per-shard credential and TLS overrides of the router's connection string
+    pub shard_config: ShardConfig,
+    /// scan shards missing a usable shard key index with a collection scan rather than refusing to scan
+    pub allow_collscan: bool,
+    /// number of chunk ranges scanned at once on each shard
+    pub concurrency: usize,
+}
+
 #[derive(Debug)]
 pub struct ShardedCluster {
     pub router: mongodb::Client,
     pub shards: HashMap<String, mongodb::Client>,
+    snapshot: bool,
+    shutdown: Shutdown,
+    progress_interval: Option<Duration>,
+    metrics: Arc<Metrics>,
+    health: Option<HealthOptions>,
+    /// each shard's scans pinned to the member chosen by the shard read preference, empty if none was set
+    shard_reads: HashMap<String, Arc<PinnedRead>>,
+    allow_collscan: bool,
+    concurrency: usize,
 }
 
 // pub struct Standalone {
@@ -28,56 +92,407 @@ pub struct ShardedCluster {
 //     pub client: mongodb::Client,
 // }
 
+/// How a namespace is distributed across the shards
+enum Routing {
+    Sharded {
+        shard_key: bson::Document,
+        ns_filter: bson::Document,
+    },
+    /// the whole collection belongs on its database's primary shard
+    Unsharded { primary: String },
+}
+
+impl Routing {
+    /// the key chunk ranges are scanned on, _id for an unsharded collection's single range
+    fn shard_key(&self) -> bson::Document {
+        match self {
+            Routing::Sharded { shard_key, .. } => shard_key.clone(),
+            Routing::Unsharded { .. } => bson::doc! { "_id": 1 },
+        }
+    }
+
+    /// the chunks currently overlapping a range, as the routing table or the database's primary shard has them now
+    async fn chunks_in_range(
+        &self,
+        router: &mongodb::Client,
+        ns: &mongodb::Namespace,
+        min: &bson::Document,
+        max: &bson::Document,
+    ) -> mongodb::error::Result<Vec<Chunk>> {
+        match self {
+            Routing::Sharded { ns_filter, .. } => {
+                db::mongos::get_chunks_in_range(router, ns_filter, min, max).await
+            }
+            Routing::Unsharded { .. } => Ok(db::mongos::get_primary_shard(router, &ns.db)
+                .await?
+                .map(Chunk::unsharded)
+                .into_iter()
+                .collect()),
+        }
+    }
+}
+
+/// Chunks of a namespace to scan, read from the routing table or, for an unsharded collection, its single range
+// only one is ever alive per scan, so its size does not matter
+#[allow(clippy::large_enum_variant)]
+enum Chunks {
+    Cursor(db::CommandCursor<Chunk>),
+    Unsharded(Option<Chunk>),
+}
+
+impl Chunks {
+    async fn try_next(&mut self) -> mongodb::error::Result<Option<Chunk>> {
+        match self {
+            Chunks::Cursor(cursor) => cursor.try_next().await,
+            Chunks::Unsharded(chunk) => Ok(chunk.take()),
+        }
+    }
+
+    async fn kill(self) {
+        if let Chunks::Cursor(cursor) = self {
+            cursor.kill().await;
+        }
+    }
+}
+
 struct CollectionMetadata {
     shard_key: Arc<bson::Document>,
-    chunk_cursor: mongodb::Cursor<Chunk>,
+    chunks: Chunks,
+    routing: Arc<Routing>,
+    total_chunks: u64,
+    /// cluster time the routing table was read at, shards are scanned at the same time when set
+    snapshot_time: Option<Arc<db::SnapshotTime>>,
 }
 
 impl ShardedCluster {
     /// return a struct containing both a connection to the specified routers and connections to each shard
-    pub async fn new(uri: &str) -> mongodb::error::Result<Self> {
-        let router = db::connect(uri).await?;
-        let shards = db::mongos::connect_to_shards(&router, uri).await?;
+    pub async fn new(uri: &str, options: ClusterOptions) -> mongodb::error::Result<Self> {
+        let router_options = mongodb::options::ClientOptions::parse(uri).await?;
+        let router = db::connect_with(router_options.clone(), None).await?;
+        let shards = db::mongos::connect_to_shards(
+            &router,
+            &router_options,
+            &options.host_map,
+            &options.shard_config,
+        )
+        .await?;
 
-        Ok(Self { router, shards })
+        let mut shard_reads = HashMap::new();
+        if let Some(read_preference) = &options.shard_read_preference {
+            for (shard_name, client) in shards.iter() {
+                let pinned = PinnedRead::new(shard_name, client, read_preference.clone()).await?;
+                shard_reads.insert(shard_name.clone(), Arc::new(pinned));
+            }
+        }
+
+        let mut snapshot = options.snapshot;
+        if snapshot {
+            let version = db::get_version(&router).await?;
+            if !util::supports_snapshot_reads(version.as_str()) {
+                log::warn!(
+                    "cluster version {} does not support snapshot reads, reading without a common cluster time",
+                    version
+                );
+                snapshot = false;
+            }
+        }
+
+        Ok(Self {
+            router,
+            shards,
+            snapshot,
+            shutdown: options.shutdown,
+            progress_interval: options.progress_interval,
+            metrics: options.metrics,
+            health: options.health,
+            shard_reads,
+            allow_collscan: options.allow_collscan,
+            concurrency: options.concurrency.max(1),
+        })
+    }
+
+    /// stop sampling shard health, so scans run unthrottled
+    pub fn disable_health(&mut self) {
+        self.health = None;
     }
 
-    /// get the number of shards currently connected to
+    // get the number of shards currently connected to
     // pub fn get_shard_count(&self) -> usize {
     //     self.shards.len()
     // }
 
+    /// how the namespace is distributed, by its chunks if it is sharded or on its database's primary shard if not
+    async fn routing(&self, ns: &mongodb::Namespace) -> mongodb::error::Result<Routing> {
+        match db::mongos::get_shard_key(&self.router, ns).await? {
+            Some(shard_key) => Ok(Routing::Sharded {
+                ns_filter: util::get_ns_filter(&self.router, ns).await?,
+                shard_key,
+            }),
+            None => match db::mongos::get_primary_shard(&self.router, &ns.db).await? {
+                Some(primary) => Ok(Routing::Unsharded { primary }),
+                None => Err(std::io::Error::other(format!(
+                    "{} is not sharded and database {} has no primary shard",
+                    ns, ns.db
+                ))
+                .into()),
+            },
+        }
+    }
+
+    #[tracing::instrument(skip_all, fields(ns = %ns))]
     async fn get_collection_metadata(
         &self,
         ns: &mongodb::Namespace,
     ) -> mongodb::error::Result<CollectionMetadata> {
-        // get the shard key in a background task
-        let router_ref = self.router.clone();
-        let ns_ref = ns.clone();
-        let shard_key_task = tokio::spawn(async move {
-            db::mongos::get_shard_key(&router_ref, &ns_ref)
-                .await
-                .unwrap()
-        });
+        let routing = self.routing(ns).await?;
+        let shard_key = Arc::new(routing.shard_key());
+        let filter = match &routing {
+            Routing::Sharded { ns_filter, .. } => ns_filter.clone(),
+            Routing::Unsharded { primary } => {
+                log::info!(
+                    "{} is not sharded, every document outside primary shard {} is an orphan",
+                    ns,
+                    primary
+                );
+                return Ok(CollectionMetadata {
+                    shard_key,
+                    chunks: Chunks::Unsharded(Some(Chunk::unsharded(primary.clone()))),
+                    routing: Arc::new(routing),
+                    total_chunks: 1,
+                    snapshot_time: None,
+                });
+            }
+        };
 
         // get the chunk cursor in a background task
         let router_ref = self.router.clone();
-        let ns_ref = ns.clone();
-        let chunks_task = tokio::spawn(async move {
-            let filter = Some(util::get_ns_filter(&router_ref, &ns_ref).await.unwrap());
-            db::mongos::get_chunk_cursor(&router_ref, filter)
-                .await
-                .unwrap()
-        });
+        let snapshot = self.snapshot;
+        let chunks_task = tokio::spawn(
+            async move {
+                let total_chunks = db::mongos::count_chunks(&router_ref, &filter).await?;
+                if snapshot {
+                    let (snapshot_time, cursor) =
+                        db::mongos::get_chunk_snapshot(&router_ref, filter).await?;
+                    return Ok((cursor, total_chunks, snapshot_time));
+                }
+                let cursor = db::mongos::get_chunk_cursor(&router_ref, Some(filter)).await?;
+                Ok::<_, mongodb::error::Error>((cursor, total_chunks, None))
+            }
+            .in_current_span(),
+        );
 
-        // join threads back together, unwrap values
-        let (shard_key_res, chunks_cursor_res) = tokio::join!(shard_key_task, chunks_task);
+        let (chunk_cursor, total_chunks, snapshot_time) = chunks_task
+            .await
+            .map_err(|err| std::io::Error::other(format!("reading chunks failed: {}", err)))??;
+        match &snapshot_time {
+            Some(time) => log::info!(
+                "reading routing table and shards at cluster time {}",
+                time.at
+            ),
+            None if snapshot => {
+                log::warn!("snapshot read of the routing table returned no cluster time")
+            }
+            None => {}
+        }
         Ok(CollectionMetadata {
-            shard_key: Arc::new(shard_key_res.unwrap()),
-            chunk_cursor: chunks_cursor_res.unwrap(),
+            shard_key,
+            chunks: Chunks::Cursor(chunk_cursor),
+            routing: Arc::new(routing),
+            total_chunks,
+            snapshot_time: snapshot_time.map(Arc::new),
         })
     }
 
+    /// create progress counters for a scan over the given number of chunks, reporting them in the background if a
+    /// progress interval was configured
+    fn start_progress(
+        &self,
+        total_chunks: u64,
+    ) -> (Arc<Progress>, Option<tokio::task::JoinHandle<()>>) {
+        let progress = Arc::new(Progress::new(
+            self.shards.keys().collect::<Vec<&String>>(),
+            total_chunks,
+        ));
+        let reporter = self
+            .progress_interval
+            .map(|interval| progress.spawn_reporter(interval));
+        (progress, reporter)
+    }
+
+    /// sample every shard's health once and keep sampling in the background, returning a dispatch throttle per shard,
+    /// or none if throttling was not configured
+    ///
+    /// the member scans read from is sampled, along with the primary if the orphans found are written
+    async fn start_health(
+        &self,
+        writes: bool,
+    ) -> (
+        HashMap<String, Arc<Throttle>>,
+        Vec<tokio::task::JoinHandle<()>>,
+    ) {
+        let Some(options) = &self.health else {
+            return (HashMap::new(), Vec::new());
+        };
+        let monitors = self.shards.iter().map(|(shard_name, client)| async move {
+            // the pinned member's criteria follow it when it is chosen again
+            let members = match self.shard_reads.get(shard_name) {
+                Some(pinned) if writes => vec![None, Some(pinned.criteria())],
+                Some(pinned) => vec![Some(pinned.criteria())],
+                None => vec![None],
+            };
+            let (throttle, handle) =
+                health::monitor(shard_name.clone(), client.clone(), members, options.clone()).await;
+            ((shard_name.clone(), throttle), handle)
+        });
+        join_all(monitors).await.into_iter().unzip()
+    }
+
+    /// a semaphore per shard limiting how many of its ranges are scanned at once
+    fn shard_slots(&self) -> HashMap<String, Arc<Semaphore>> {
+        self.shards
+            .keys()
+            .map(|shard_name| {
+                (
+                    shard_name.clone(),
+                    Arc::new(Semaphore::new(self.concurrency)),
+                )
+            })
+            .collect()
+    }
+
+    /// check every shard for an index range scans on the shard key can use, returning how each shard is scanned or
+    /// an error naming the shards that cannot be
+    async fn range_scans(
+        &self,
+        ns: &mongodb::Namespace,
+        shard_key: &bson::Document,
+    ) -> mongodb::error::Result<HashMap<String, Arc<RangeScan>>> {
+        let checks = self.shards.iter().map(|(shard_name, client)| async move {
+            let scan =
+                shard_key_index::range_scan(shard_name, client, ns, shard_key, self.allow_collscan)
+                    .await;
+            (shard_name.clone(), scan)
+        });
+        let mut scans = HashMap::new();
+        let mut refused = Vec::new();
+        for (shard_name, scan) in join_all(checks).await {
+            match scan? {
+                Some(scan) => {
+                    scans.insert(shard_name, Arc::new(scan));
+                }
+                None => refused.push(shard_name),
+            }
+        }
+        if !refused.is_empty() {
+            refused.sort();
+            return Err(std::io::Error::other(format!(
+                "cannot scan shard(s) {} without a usable index on shard key {}, create it or rerun with --allow-collscan",
+                refused.join(", "),
+                shard_key
+            ))
+            .into());
+        }
+        Ok(scans)
+    }
+
+    /// read every chunk of the namespace and check that its routing table is consistent
+    pub async fn check_metadata(
+        &self,
+        ns: &mongodb::Namespace,
+    ) -> mongodb::error::Result<routing_table::Report> {
+        log::info!("checking routing table of namespace {}", &ns.to_string());
+        let (shard_key, filter) = match self.routing(ns).await? {
+            Routing::Sharded {
+                shard_key,
+                ns_filter,
+            } => (shard_key, ns_filter),
+            Routing::Unsharded { primary } => {
+                return Err(std::io::Error::other(format!(
+                    "{} is not sharded, it has no routing table beyond primary shard {}",
+                    ns, primary
+                ))
+                .into())
+            }
+        };
+        let mut chunk_cursor = db::mongos::get_chunk_cursor(&self.router, Some(filter)).await?;
+        let mut chunks = Vec::new();
+        while let Some(chunk) = chunk_cursor.try_next().await? {
+            chunks.push(chunk);
+        }
+        let shards = db::mongos::get_shard_names(&self.router).await?;
+        let epoch = db::mongos::get_collection(&self.router, ns)
+            .await?
+            .and_then(|collection| collection.get_object_id("lastmodEpoch").ok());
+        Ok(routing_table::check(chunks, &shard_key, &shards, epoch))
+    }
+
+    /// compare every shard's copy of the namespace against config.collections and against each other
+    pub async fn check_collection(
+        &self,
+        ns: &mongodb::Namespace,
+    ) -> mongodb::error::Result<shard_collections::Report> {
+        log::info!("checking collection {} on every shard", &ns.to_string());
+        let config = match db::mongos::get_collection(&self.router, ns).await? {
+            Some(doc) => ConfigCollection::from_document(&doc),
+            None => {
+                return Err(
+                    std::io::Error::other(format!("{} is not in config.collections", ns)).into(),
+                )
+            }
+        };
+        let checks = self.shards.iter().map(|(shard_name, client)| async move {
+            let collection = match db::list_collection(client, ns).await? {
+                Some(info) => {
+                    let indexes = db::list_indexes(client, ns).await?.unwrap_or_default();
+                    Some(ShardCollection::new(&info, indexes))
+                }
+                None => None,
+            };
+            Ok::<_, mongodb::error::Error>((shard_name.clone(), collection))
+        });
+        let shards = join_all(checks)
+            .await
+            .into_iter()
+            .collect::<mongodb::error::Result<BTreeMap<_, _>>>()?;
+        Ok(shard_collections::compare(&config, &shards))
+    }
+
+    /// find shards that own no chunk of the namespace but still hold documents of it, comparing each shard's
+    /// estimated document count against the chunks it owns
+    pub async fn find_remnants(
+        &self,
+        ns: &mongodb::Namespace,
+    ) -> mongodb::error::Result<Vec<Remnant>> {
+        let chunks = match self.routing(ns).await? {
+            Routing::Sharded { ns_filter, .. } => {
+                db::mongos::count_chunks_by_shard(&self.router, &ns_filter).await?
+            }
+            Routing::Unsharded { primary } => HashMap::from([(primary, 1)]),
+        };
+        let counts = self.shards.iter().map(|(shard_name, client)| async move {
+            let documents = db::count(client, ns, true).await?;
+            Ok::<_, mongodb::error::Error>((shard_name.clone(), documents))
+        });
+        let documents = join_all(counts)
+            .await
+            .into_iter()
+            .collect::<mongodb::error::Result<HashMap<_, _>>>()?;
+        Ok(orphan::remnants(&documents, &chunks))
+    }
+
+    /// warn about shards whose whole copy of the namespace is orphaned, before scanning them chunk by chunk
+    async fn warn_remnants(&self, ns: &mongodb::Namespace) -> mongodb::error::Result<()> {
+        for remnant in self.find_remnants(ns).await? {
+            log::warn!(
+                "shard {} owns no chunks of {} but holds ~{} document(s), every one of them is orphaned",
+                remnant.shard,
+                ns,
+                remnant.documents
+            );
+        }
+        Ok(())
+    }
+
     pub async fn estimate_orphaned(&self, ns: &mongodb::Namespace) -> mongodb::error::Result<u64> {
         log::info!("estimating orphans on namespace {}", &ns.to_string());
         let mongos = self.router.clone();
@@ -91,7 +506,7 @@ impl ShardedCluster {
             tokio::spawn(async move { db::count(&mongos, &ns_ref, false).await.unwrap() });
 
         let (estimated_count, actual_count) = tokio::join!(estimated_count_task, actual_count_task);
-        return Ok(estimated_count.unwrap() - actual_count.unwrap());
+        Ok(estimated_count.unwrap() - actual_count.unwrap())
     }
 
     /// return orphans -- a struct that has summary data and a verbose map of orphans for each shard
@@ -101,140 +516,802 @@ impl ShardedCluster {
     ) -> mongodb::error::Result<OrphanSummary> {
         log::info!("searching for orphans on namespace {}", &ns.to_string());
         let ns = Arc::new(ns.to_owned());
-        let mut metadata = Self::get_collection_metadata(&self, &ns.clone()).await?;
+        let mut metadata = Self::get_collection_metadata(self, &ns.clone()).await?;
 
         log::debug!(
             "shard key for ns {} is {}",
             &ns,
             &metadata.shard_key.to_string()
         );
+        let scans = self.range_scans(&ns, &metadata.shard_key).await?;
+        self.warn_remnants(&ns).await?;
 
         // create a multi-producer single consumer channel and listen for orphans, adding them to the summary as they are processed
         let (tx, mut rx) = tokio::sync::mpsc::channel::<Orphan>(BUFFER_SIZE);
         let mut summary = OrphanSummary::new(self.shards.keys().collect::<Vec<&String>>());
-        let handle = tokio::spawn(async move {
-            while let Some(orphan) = rx.recv().await {
-                log::debug!("adding orphan {:?}", &orphan);
-                summary.add(orphan);
+        let handle = tokio::spawn(
+            async move {
+                while let Some(orphan) = rx.recv().await {
+                    log::debug!("adding orphan {:?}", &orphan);
+                    summary.add(orphan);
+                }
+                summary
             }
-            summary
-        });
+            .in_current_span(),
+        );
+
+        let (progress, reporter) = self.start_progress(metadata.total_chunks);
+        let (throttles, monitors) = self.start_health(false).await;
+        let slots = self.shard_slots();
 
         // iterate through chunks cursor, spawning background threads to send each chunk to every shard except its own, if any results are found put them on the orphan channel
         let mut tasks = Vec::new();
-        while let Some(chunk) = metadata.chunk_cursor.try_next().await? {
+        while let Some(chunk) = metadata.chunks.try_next().await? {
+            if self.shutdown.is_triggered() {
+                break;
+            }
             let shards = self.shards.clone();
             let chunk = Arc::new(chunk);
 
             let shards_minus_self = shards
                 .into_iter()
-                .filter(|(shard_name, _)| shard_name.to_owned() != chunk.clone().shard);
+                .filter(|(shard_name, _)| *shard_name != chunk.clone().shard);
             for (shard_name, client) in shards_minus_self {
                 let ns = ns.clone();
                 let shard_key = metadata.shard_key.clone();
+                let scan = scans[&shard_name].clone();
                 let chunk = chunk.clone();
                 let tx = tx.clone();
-                let handle = tokio::spawn(async move {
-                    let mut chunk_ids =
-                        db::find_id_range(&client, &ns, &shard_key.clone(), &chunk.min, &chunk.max)
-                            .await;
-                    while let Some(id) = chunk_ids.try_next().await.unwrap() {
-                        log::debug!("found {:?} on shard {}", &id, &shard_name);
-                        let orphan = Orphan {
-                            shard: shard_name.clone(),
-                            id: id,
+                let snapshot_time = metadata.snapshot_time.clone();
+                let pinned = self.shard_reads.get(&shard_name).cloned();
+                let shutdown = self.shutdown.clone();
+                let progress = progress.clone();
+                let metrics = self.metrics.clone();
+                let throttle = throttles.get(&shard_name).cloned();
+                let slot = slots[&shard_name].clone();
+                let span = range_span(&shard_name, &chunk);
+                let handle = tokio::spawn(
+                    async move {
+                        let Some(_permit) = dispatch(throttle.as_deref(), slot, &shutdown).await?
+                        else {
+                            return Ok(());
                         };
-                        tx.send(orphan).await.unwrap();
+                        let started = Instant::now();
+                        let mut found = 0;
+                        let mut chunk_ids = db::find_id_range(
+                            &client,
+                            &ns,
+                            &shard_key,
+                            &scan,
+                            &chunk.min,
+                            &chunk.max,
+                            snapshot_time.as_deref(),
+                            pinned.as_deref(),
+                        )
+                        .await?;
+                        loop {
+                            if shutdown.is_triggered() {
+                                chunk_ids.kill().await;
+                                break;
+                            }
+                            let Some(id) = chunk_ids.try_next().await? else {
+                                break;
+                            };
+                            log::debug!("found {:?} on shard {}", &id, &shard_name);
+                            let orphan = Orphan {
+                                shard: shard_name.clone(),
+                                owner: chunk.shard.clone(),
+                                id,
+                            };
+                            tx.send(orphan).await.unwrap();
+                            progress.orphan_found();
+                            found += 1;
+                        }
+                        progress.range_done(&shard_name);
+                        metrics.range_scanned(&ns, &shard_name, started.elapsed(), found);
+                        drop(tx);
+                        Ok::<_, mongodb::error::Error>(())
                     }
-                    drop(tx);
-                });
+                    .instrument(span),
+                );
                 tasks.push(handle);
             }
         }
 
         // ensure all tasks have finished, drop the original tx and wait for the reciever to process it all before returning the completed summary
-        join_all(tasks).await;
+        let mut result = Ok(());
+        for task in join_all(tasks).await {
+            if let Err(err) = task.unwrap() {
+                log::error!("scan failed: {}", err);
+                result = result.and(Err(err));
+            }
+        }
         drop(tx);
-        let summary = handle.await.unwrap();
+        let mut summary = handle.await.unwrap();
+        if let Some(reporter) = reporter {
+            reporter.abort();
+            progress.log();
+        }
+        monitors.iter().for_each(|monitor| monitor.abort());
+        if self.shutdown.is_triggered() {
+            metadata.chunks.kill().await;
+            summary.mark_incomplete();
+        }
+
+        result.map(|_| summary)
+    }
+
+    /// find orphans and compare each one against the document with the same _id on the shard that owns its chunk
+    pub async fn diff_orphaned(
+        &self,
+        ns: &mongodb::Namespace,
+    ) -> mongodb::error::Result<DiffReport> {
+        let summary = self.find_orphaned(ns).await?;
+        log::info!(
+            "comparing {} orphans against their owning shards",
+            summary.cluster_total()
+        );
+
+        let mut comparisons = futures::stream::iter(summary.orphans().cloned())
+            .map(|orphan| async move {
+                let orphan_client = &self.shards[&orphan.shard];
+                let orphan_doc = db::find_by_id(orphan_client, ns, &orphan.id._id, None).await?;
+                let owned_doc = match self.shards.get(&orphan.owner) {
+                    Some(owner_client) => {
+                        db::find_by_id(owner_client, ns, &orphan.id._id, None).await?
+                    }
+                    None => {
+                        log::warn!(
+                            "not connected to owning shard {}, skipping {:?}",
+                            &orphan.owner,
+                            &orphan.id
+                        );
+                        return Ok(None);
+                    }
+                };
+                Ok::<_, mongodb::error::Error>(Some((orphan, orphan_doc, owned_doc)))
+            })
+            .buffer_unordered(DIFF_CONCURRENCY);
+
+        let mut report = DiffReport::default();
+        while let Some(comparison) = comparisons.try_next().await? {
+            if self.shutdown.is_triggered() {
+                break;
+            }
+            match comparison {
+                Some((orphan, Some(orphan_doc), owned_doc)) => {
+                    report.add(orphan, &orphan_doc, owned_doc.as_ref())
+                }
+                Some((orphan, None, _)) => {
+                    log::warn!(
+                        "orphan {:?} is no longer on shard {}, skipping",
+                        &orphan.id,
+                        &orphan.shard
+                    );
+                }
+                None => {}
+            }
+        }
+        Ok(report)
+    }
+
+    /// move orphans whose _id does not exist on the owning shard back through the router, then remove them from the
+    /// shard they were found on
+    ///
+    /// if an archive namespace is provided, each orphan is copied there (through the router) before anything is
+    /// changed. every write uses the write concern and retries of the write options. in dry run mode nothing is written
+    /// and the summary reports what would have happened
+    ///
+    /// the first failed read or write stops the run, and is recorded in the summary along with everything moved before
+    /// it rather than returned as an error
+    pub async fn recover_orphaned(
+        &self,
+        ns: &mongodb::Namespace,
+        archive_ns: Option<&mongodb::Namespace>,
+        options: &WriteOptions,
+        dry_run: bool,
+    ) -> mongodb::error::Result<RecoverySummary> {
+        let report = self.diff_orphaned(ns).await?;
+        log::info!(
+            "recovering {} unique orphans on namespace {}, skipping {} that also exist on their owning shard",
+            report.unique.len(),
+            &ns.to_string(),
+            report.identical.len() + report.divergent.len(),
+        );
+
+        let mut summary = RecoverySummary {
+            skipped: report.identical.len() + report.divergent.len(),
+            ..Default::default()
+        };
+        for orphan in report.unique {
+            if self.shutdown.is_triggered() {
+                break;
+            }
+            let client = &self.shards[&orphan.shard];
+            // the copy moved to the owner must be the one about to be deleted, never a lagging secondary's
+            let primary = SelectionCriteria::ReadPreference(ReadPreference::Primary);
+            let doc = match db::find_by_id(client, ns, &orphan.id._id, Some(primary)).await {
+                Ok(Some(doc)) => doc,
+                Err(err) => {
+                    summary.errors.push(format!(
+                        "cannot read orphan {:?} on shard {}, nothing was written for it: {}",
+                        &orphan.id._id, &orphan.shard, err
+                    ));
+                    break;
+                }
+                Ok(None) => {
+                    log::warn!(
+                        "orphan {:?} is no longer on shard {}, skipping",
+                        &orphan.id._id,
+                        &orphan.shard
+                    );
+                    summary.skipped += 1;
+                    continue;
+                }
+            };
+
+            if dry_run {
+                if archive_ns.is_some() {
+                    summary
+                        .plan
+                        .add(Action::Archive, &orphan.owner, &orphan.id._id);
+                    summary.archived += 1;
+                }
+                summary
+                    .plan
+                    .add(Action::Insert, &orphan.owner, &orphan.id._id);
+                summary
+                    .plan
+                    .add(Action::Delete, &orphan.shard, &orphan.id._id);
+                summary.recovered += 1;
+                continue;
+            }
+
+            if let Some(archive_ns) = archive_ns {
+                // the _id is set here rather than by the driver so that a retry cannot archive the orphan twice
+                let archived = bson::doc! {
+                    "_id": bson::oid::ObjectId::new(),
+                    "orphan": &doc,
+                    "ns": ns.to_string(),
+                    "shard": &orphan.shard,
+                    "owner": &orphan.owner,
+                    "archivedAt": bson::DateTime::now(),
+                };
+                let (result, retried) = write::with_retries(options, "archive insert", || {
+                    db::insert(
+                        &self.router,
+                        archive_ns,
+                        &archived,
+                        options.write_concern.clone(),
+                    )
+                })
+                .await;
+                summary.retried += retried;
+                match result {
+                    Ok(()) => {}
+                    // an attempt that failed on the network after all
+                    Err(err) if retried > 0 && util::is_duplicate_key_error(&err) => {}
+                    Err(err) => {
+                        self.metrics.writes(archive_ns, ROUTER, 1, 0, 1);
+                        summary.errors.push(format!(
+                            "archiving orphan {:?} of shard {} to {} failed, nothing else was written for it: {}",
+                            &orphan.id._id, &orphan.shard, archive_ns, err
+                        ));
+                        break;
+                    }
+                }
+                self.metrics.writes(archive_ns, ROUTER, 1, 1, 0);
+                summary.archived += 1;
+            }
 
+            // inserting through the router routes the document to the shard that owns its chunk
+            let (result, retried) = write::with_retries(options, "insert on owning shard", || {
+                db::insert(&self.router, ns, &doc, options.write_concern.clone())
+            })
+            .await;
+            summary.retried += retried;
+            if let Err(err) = result {
+                // after a retry this may also be the first attempt having gone through, either way the orphan is
+                // left in place
+                if util::is_duplicate_key_error(&err) {
+                    self.metrics.writes(ns, &orphan.owner, 1, 0, 0);
+                    log::warn!(
+                        "{:?} was written to owning shard {} since the scan, leaving orphan in place",
+                        &orphan.id._id,
+                        &orphan.owner
+                    );
+                    summary.conflicts += 1;
+                    continue;
+                }
+                self.metrics.writes(ns, &orphan.owner, 1, 0, 1);
+                summary.errors.push(format!(
+                    "inserting orphan {:?} on owning shard {} failed, it is still only on shard {}{}: {}",
+                    &orphan.id._id,
+                    &orphan.owner,
+                    &orphan.shard,
+                    if archive_ns.is_some() {
+                        " (and archived)"
+                    } else {
+                        ""
+                    },
+                    err
+                ));
+                break;
+            }
+            self.metrics.writes(ns, &orphan.owner, 1, 1, 0);
+
+            let (result, retried) = write::with_retries(options, "orphan delete", || {
+                db::delete_by_id(client, ns, &orphan.id._id, options.write_concern.clone())
+            })
+            .await;
+            summary.retried += retried;
+            if let Err(err) = result {
+                self.metrics.writes(ns, &orphan.shard, 1, 0, 1);
+                summary.errors.push(format!(
+                    "orphan {:?} was inserted on owning shard {} but deleting it from shard {} failed, it is now on \
+                     both: {}",
+                    &orphan.id._id, &orphan.owner, &orphan.shard, err
+                ));
+                break;
+            }
+            self.metrics.writes(ns, &orphan.shard, 1, 1, 0);
+            log::debug!(
+                "moved {:?} from shard {} to owning shard {}",
+                &orphan.id._id,
+                &orphan.shard,
+                &orphan.owner
+            );
+            summary.recovered += 1;
+        }
         Ok(summary)
     }
 
-    /// return orphans -- a struct that has summary data and a verbose map of orphans for each shard
+    /// remove the marker field (and its run metadata) set by update_orphaned from every shard, optionally only for
+    /// a single run id, in batches of the ids found carrying it, returning the matched/modified counts and errors of
+    /// each shard
+    ///
+    /// in dry run mode nothing is written, the ids that would be unmarked are added to the plan and counted as matched
+    pub async fn unmark_orphaned(
+        &self,
+        ns: &mongodb::Namespace,
+        field: &str,
+        run_id: Option<&str>,
+        options: &WriteOptions,
+        plan: Option<&mut DryRunPlan>,
+    ) -> mongodb::error::Result<WriteSummary> {
+        log::info!(
+            "unmarking field {} on namespace {}{}",
+            field,
+            &ns.to_string(),
+            run_id
+                .map(|id| format!(" for run {}", id))
+                .unwrap_or_default()
+        );
+        let filter = marker::unmark_filter(field, run_id);
+        let mut summary = WriteSummary::default();
+
+        if let Some(plan) = plan {
+            for (shard_name, client) in self.shards.iter() {
+                let mut ids = db::find_ids(client, ns, filter.clone()).await?;
+                let mut shard_summary = ShardWriteSummary::default();
+                while let Some(id) = ids.try_next().await? {
+                    plan.add(Action::Update, shard_name, &id._id);
+                    shard_summary.matched += 1;
+                }
+                summary.merge(shard_name, shard_summary);
+            }
+            return Ok(summary);
+        }
+
+        let update = marker::unmark_update(field);
+        let tasks = self.shards.iter().map(|(shard_name, client)| {
+            let ns = ns.clone();
+            let client = client.clone();
+            let filter = filter.clone();
+            let update = update.clone();
+            let options = options.clone();
+            let shutdown = self.shutdown.clone();
+            let shard_name = shard_name.clone();
+            let span = tracing::info_span!("shard", shard = %shard_name);
+            tokio::spawn(
+                async move {
+                    let mut summary = ShardWriteSummary::default();
+                    let mut marked = db::find_ids(&client, &ns, filter).await?;
+                    let mut ids = Vec::with_capacity(options.batch_size);
+                    while !shutdown.is_triggered() {
+                        let id = marked.try_next().await?;
+                        if let Some(id) = &id {
+                            ids.push(id._id);
+                        }
+                        if ids.len() >= options.batch_size || (id.is_none() && !ids.is_empty()) {
+                            write::update_batch(
+                                &client,
+                                &ns,
+                                &ids,
+                                &update,
+                                &options,
+                                &mut summary,
+                            )
+                            .await;
+                            ids.clear();
+                        }
+                        if id.is_none() {
+                            break;
+                        }
+                    }
+                    log::debug!(
+                        "unmarked {} document(s) on shard {}",
+                        summary.modified,
+                        &shard_name
+                    );
+                    Ok::<_, mongodb::error::Error>((shard_name, summary))
+                }
+                .instrument(span),
+            )
+        });
+
+        for result in join_all(tasks).await {
+            let (shard_name, shard_summary) = result.unwrap()?;
+            self.metrics.writes(
+                ns,
+                &shard_name,
+                shard_summary.batches,
+                shard_summary.modified,
+                shard_summary.errors.len(),
+            );
+            summary.merge(&shard_name, shard_summary);
+        }
+        if self.shutdown.is_triggered() {
+            summary.mark_incomplete();
+        }
+        Ok(summary)
+    }
+
+    /// scan for orphans and return the updates that update_orphaned would issue, without writing anything
+    pub async fn plan_update(&self, ns: &mongodb::Namespace) -> mongodb::error::Result<DryRunPlan> {
+        let summary = self.find_orphaned(ns).await?;
+        let mut plan = DryRunPlan::default();
+        for orphan in summary.orphans() {
+            plan.add(Action::Update, &orphan.shard, &orphan.id._id);
+        }
+        Ok(plan)
+    }
+
+    /// mark every orphan on every shard in batches, returning the matched/modified counts and errors of each shard
     pub async fn update_orphaned(
         &self,
         ns: &mongodb::Namespace,
         _: Option<&mongodb::Namespace>,
-    ) -> mongodb::error::Result<()> {
+        marker: &Marker,
+        options: &WriteOptions,
+    ) -> mongodb::error::Result<WriteSummary> {
         log::info!("marking orphans on namespace {}", &ns.to_string());
+        let marker = Arc::new(marker.clone());
+        let options = Arc::new(options.clone());
 
-        let mut metadata = Self::get_collection_metadata(&self, &ns.clone()).await?;
+        let mut metadata = Self::get_collection_metadata(self, &ns.clone()).await?;
 
         log::debug!(
             "shard key for ns {} is {}",
             &ns,
             &metadata.shard_key.to_string()
         );
+        let scans = self.range_scans(ns, &metadata.shard_key).await?;
+        self.warn_remnants(ns).await?;
+
+        let (progress, reporter) = self.start_progress(metadata.total_chunks);
+        let (throttles, monitors) = self.start_health(true).await;
+        let slots = self.shard_slots();
 
         // iterate through chunks cursor, spawning background threads to send each chunk to be marked on every shard except its own
         let mut tasks = Vec::new();
-        while let Some(chunk) = metadata.chunk_cursor.try_next().await? {
+        while let Some(chunk) = metadata.chunks.try_next().await? {
+            if self.shutdown.is_triggered() {
+                break;
+            }
             let shards = self.shards.clone();
             let chunk = Arc::new(chunk);
 
             shards
                 .into_iter()
-                .filter(|(shard_name, _)| shard_name.to_owned() != chunk.clone().shard)
+                .filter(|(shard_name, _)| *shard_name != chunk.clone().shard)
                 .for_each(|(shard_name, client)| {
                     let ns = ns.clone();
 
                     let shard_key = metadata.shard_key.clone();
+                    let scan = scans[&shard_name].clone();
                     let chunk = chunk.clone();
-                    let handle = tokio::spawn(async move {
-                        let mut chunk_ids = db::find_id_range(
-                            &client,
-                            &ns,
-                            &shard_key.clone(),
-                            &chunk.min,
-                            &chunk.max,
-                        )
-                        .await;
-                        let mut ids = vec![];
-                        while let Some(id) = chunk_ids.try_next().await.unwrap() {
-                            log::debug!("found {:?} on shard {}", &id, &shard_name);
-                            ids.push(id._id);
+                    let marker = marker.clone();
+                    let options = options.clone();
+                    let router = self.router.clone();
+                    let routing = metadata.routing.clone();
+                    let snapshot_time = metadata.snapshot_time.clone();
+                    let pinned = self.shard_reads.get(&shard_name).cloned();
+                    let shutdown = self.shutdown.clone();
+                    let progress = progress.clone();
+                    let metrics = self.metrics.clone();
+                    let throttle = throttles.get(&shard_name).cloned();
+                    let slot = slots[&shard_name].clone();
+                    let span = range_span(&shard_name, &chunk);
+                    let handle = tokio::spawn(
+                        async move {
+                            let mut summary = ShardWriteSummary::default();
+                            let started = Instant::now();
+                            let mut found = 0;
+                            let (_permit, chunk_ids) =
+                                match dispatch(throttle.as_deref(), slot, &shutdown).await {
+                                    // shutdown, the range is left unscanned
+                                    Ok(None) => return (shard_name, summary),
+                                    Ok(Some(permit)) => {
+                                        let chunk_ids = db::find_id_range(
+                                            &client,
+                                            &ns,
+                                            &shard_key,
+                                            &scan,
+                                            &chunk.min,
+                                            &chunk.max,
+                                            snapshot_time.as_deref(),
+                                            pinned.as_deref(),
+                                        )
+                                        .await;
+                                        (Some(permit), chunk_ids)
+                                    }
+                                    Err(err) => (None, Err(err)),
+                                };
+                            let mut chunk_ids = match chunk_ids {
+                                Ok(chunk_ids) => chunk_ids,
+                                Err(err) => {
+                                    summary.errors.push(format!(
+                                        "scan of chunk {} -> {} failed: {}",
+                                        chunk.min, chunk.max, err
+                                    ));
+                                    progress.range_done(&shard_name);
+                                    metrics.inc(
+                                        "orphanage_errors_total",
+                                        metrics::shard_labels(&ns, &shard_name),
+                                        1.0,
+                                    );
+                                    return (shard_name, summary);
+                                }
+                            };
+                            let batch = VerifiedBatch {
+                                router: &router,
+                                routing: &routing,
+                                chunk: &chunk,
+                                shard_name: &shard_name,
+                            };
+                            let mut ids = Vec::with_capacity(options.batch_size);
+                            loop {
+                                if shutdown.is_triggered() {
+                                    chunk_ids.kill().await;
+                                    ids.clear();
+                                    break;
+                                }
+                                let id = match chunk_ids.try_next().await {
+                                    Ok(Some(id)) => id,
+                                    Ok(None) => break,
+                                    Err(err) => {
+                                        summary.errors.push(format!(
+                                            "scan of chunk {} -> {} failed: {}",
+                                            chunk.min, chunk.max, err
+                                        ));
+                                        break;
+                                    }
+                                };
+                                log::debug!("found {:?} on shard {}", &id, &shard_name);
+                                progress.orphan_found();
+                                found += 1;
+                                ids.push(id._id);
 
-                            if ids.len() > 1000 {
-                                let _ = client
-                                    .database(ns.db.as_str())
-                                    .collection::<()>(ns.coll.as_str())
-                                    .update_many(
-                                        bson::doc! { "_id": { "$in": ids.clone()}},
-                                        bson::doc! { "$set": { "orphaned": true }},
-                                        None,
-                                    )
-                                    .await;
-
-                                ids.clear();
+                                if ids.len() >= options.batch_size {
+                                    match wait(throttle.as_deref(), &shutdown).await {
+                                        Ok(true) => {}
+                                        Ok(false) => {
+                                            chunk_ids.kill().await;
+                                            ids.clear();
+                                            break;
+                                        }
+                                        Err(err) => {
+                                            summary.errors.push(format!(
+                                                "scan of chunk {} -> {} stopped, skipped {} id(s): {}",
+                                                chunk.min,
+                                                chunk.max,
+                                                ids.len(),
+                                                err
+                                            ));
+                                            summary.skipped += ids.len() as u64;
+                                            ids.clear();
+                                            break;
+                                        }
+                                    }
+                                    batch
+                                        .update(&client, &ns, &ids, &marker, &options, &mut summary)
+                                        .await;
+                                    ids.clear();
+                                }
                             }
-                        }
 
-                        let _ = client
-                            .database(ns.db.as_str())
-                            .collection::<()>(ns.coll.as_str())
-                            .update_many(
-                                bson::doc! { "_id": { "$in": ids.clone()}},
-                                bson::doc! { "$set": { "orphaned": true }},
-                                None,
-                            )
-                            .await;
-                    });
+                            if !ids.is_empty() {
+                                match wait(throttle.as_deref(), &shutdown).await {
+                                    Err(err) => {
+                                        summary.errors.push(format!(
+                                            "last batch of chunk {} -> {} skipped {} id(s): {}",
+                                            chunk.min,
+                                            chunk.max,
+                                            ids.len(),
+                                            err
+                                        ));
+                                        summary.skipped += ids.len() as u64;
+                                    }
+                                    Ok(false) => {}
+                                    Ok(true) => {
+                                        batch
+                                            .update(
+                                                &client,
+                                                &ns,
+                                                &ids,
+                                                &marker,
+                                                &options,
+                                                &mut summary,
+                                            )
+                                            .await
+                                    }
+                                }
+                            }
+                            progress.range_done(&shard_name);
+                            metrics.range_scanned(&ns, &shard_name, started.elapsed(), found);
+                            metrics.writes(
+                                &ns,
+                                &shard_name,
+                                summary.batches,
+                                summary.modified,
+                                summary.errors.len(),
+                            );
+                            (shard_name, summary)
+                        }
+                        .instrument(span),
+                    );
                     tasks.push(handle);
                 });
         }
-        // ensure all tasks have finished, drop the original tx and wait for the reciever to process it all before returning the completed summary
-        join_all(tasks).await;
 
-        Ok(())
+        // ensure all tasks have finished, folding each task's results into the summary for its shard
+        let mut summary = WriteSummary::default();
+        for result in join_all(tasks).await {
+            let (shard_name, shard_summary) = result.unwrap();
+            summary.merge(&shard_name, shard_summary);
+        }
+        if let Some(reporter) = reporter {
+            reporter.abort();
+            progress.log();
+        }
+        monitors.iter().for_each(|monitor| monitor.abort());
+        if self.shutdown.is_triggered() {
+            metadata.chunks.kill().await;
+            summary.mark_incomplete();
+        }
+
+        Ok(summary)
+    }
+}
+
+/// wait until a shard may be queried, if health throttling is on, returning false once shutdown is triggered
+async fn wait(throttle: Option<&Throttle>, shutdown: &Shutdown) -> mongodb::error::Result<bool> {
+    match throttle {
+        Some(throttle) => throttle.wait(shutdown).await,
+        None => Ok(!shutdown.is_triggered()),
+    }
+}
+
+/// wait until a shard may be queried, if health throttling is on, then take one of its scan slots, held for the whole
+/// range scan
+///
+/// a range that waited for a slot while the shard was paused goes back to waiting on the throttle, so ranges queued
+/// behind the busy slots do not all start once the shard is paused. returns none once shutdown is triggered, so the
+/// range is skipped rather than scanned
+async fn dispatch(
+    throttle: Option<&Throttle>,
+    slot: Arc<Semaphore>,
+    shutdown: &Shutdown,
+) -> mongodb::error::Result<Option<OwnedSemaphorePermit>> {
+    loop {
+        if !wait(throttle, shutdown).await? {
+            return Ok(None);
+        }
+        let permit = slot
+            .clone()
+            .acquire_owned()
+            .await
+            .map_err(std::io::Error::other)?;
+        if shutdown.is_triggered() {
+            return Ok(None);
+        }
+        if throttle.is_none_or(|throttle| !throttle.is_paused()) {
+            return Ok(Some(permit));
+        }
+    }
+}
+
+/// span carried by the task scanning a chunk's range on a shard that does not own it
+fn range_span(shard_name: &str, chunk: &Chunk) -> tracing::Span {
+    tracing::info_span!(
+        "range",
+        shard = %shard_name,
+        owner = %chunk.shard,
+        chunk_min = %chunk.min,
+        chunk_max = %chunk.max,
+    )
+}
+
+/// A batch of orphan ids found in a chunk's range on a shard that does not own it, whose ownership is re-read from the
+/// routing table right before it is written
+struct VerifiedBatch<'a> {
+    router: &'a mongodb::Client,
+    routing: &'a Routing,
+    chunk: &'a Chunk,
+    shard_name: &'a str,
+}
+
+impl VerifiedBatch<'_> {
+    /// re-verify ownership of the chunk's range, then mark the ids -- skipping them entirely if the range may now be
+    /// owned by the shard being written to, and stamping the new owner if the range migrated elsewhere
+    #[tracing::instrument(name = "batch", skip_all, fields(ids = ids.len()))]
+    async fn update(
+        &self,
+        client: &mongodb::Client,
+        ns: &mongodb::Namespace,
+        ids: &[bson::oid::ObjectId],
+        marker: &Marker,
+        options: &WriteOptions,
+        summary: &mut ShardWriteSummary,
+    ) {
+        let current = match self
+            .routing
+            .chunks_in_range(self.router, ns, &self.chunk.min, &self.chunk.max)
+            .await
+        {
+            Ok(current) => current,
+            Err(err) => {
+                summary.errors.push(format!(
+                    "cannot re-verify ownership of chunk {} -> {}, skipped {} id(s): {}",
+                    self.chunk.min,
+                    self.chunk.max,
+                    ids.len(),
+                    err
+                ));
+                summary.skipped += ids.len() as u64;
+                return;
+            }
+        };
+
+        let owner = match self.chunk.ownership(&current, self.shard_name) {
+            Ownership::Unchanged => self.chunk.shard.clone(),
+            Ownership::Moved(owner) => {
+                if owner != self.chunk.shard {
+                    log::info!(
+                        "chunk {} -> {} moved from shard {} to {} since the scan, reclassifying {} id(s)",
+                        self.chunk.min,
+                        self.chunk.max,
+                        self.chunk.shard,
+                        owner,
+                        ids.len()
+                    );
+                    summary.reclassified += ids.len() as u64;
+                }
+                owner
+            }
+            Ownership::Changed => {
+                log::warn!(
+                    "routing for chunk {} -> {} changed since the scan and may now include shard {}, skipping {} id(s)",
+                    self.chunk.min,
+                    self.chunk.max,
+                    self.shard_name,
+                    ids.len()
+                );
+                summary.skipped += ids.len() as u64;
+                return;
+            }
+        };
+        write::update_batch(client, ns, ids, &marker.update(&owner), options, summary).await;
     }
 }
@@ -1,5 +1,12 @@
+use std::{collections::VecDeque, marker::PhantomData};
+
 use mongodb::bson;
-use serde::Deserialize;
+use mongodb::options::SelectionCriteria;
+use serde::{de::DeserializeOwned, Deserialize};
+
+use crate::{
+    read_preference::PinnedRead, shard_config::ShardSettings, shard_key_index::RangeScan, util,
+};
 
 /// A thin wrapper around an objectId for deseralization
 #[derive(Debug, Clone, Deserialize)]
@@ -7,16 +14,31 @@ pub struct Id {
     pub _id: bson::oid::ObjectId,
 }
 
-/// connects to instance at uri, specify options and credentials according to mongodb docs (https://www.mongodb.com/docs/manual/reference/connection-string/)
-pub async fn connect(uri: &str) -> mongodb::error::Result<mongodb::Client> {
-    let mut options = mongodb::options::ClientOptions::parse(uri).await?;
+/// connects with the given options, parsed from a uri as in the mongodb docs
+/// (https://www.mongodb.com/docs/manual/reference/connection-string/), applying a shard's credential and TLS overrides
+/// on top of them
+pub async fn connect_with(
+    mut options: mongodb::options::ClientOptions,
+    settings: Option<&ShardSettings>,
+) -> mongodb::error::Result<mongodb::Client> {
+    let hosts = options
+        .hosts
+        .iter()
+        .map(|host| host.to_string())
+        .collect::<Vec<_>>()
+        .join(",");
+    if let Some(settings) = settings {
+        settings
+            .apply(&mut options)
+            .map_err(std::io::Error::other)?;
+    }
     options.app_name = Some("orphanage".to_string());
     let client = mongodb::Client::with_options(options)?;
     client
         .database("admin")
         .run_command(bson::doc! {"ping": 1}, None)
         .await?;
-    log::debug!("Connected to {}", uri);
+    log::debug!("Connected to {}", hosts);
     Ok(client)
 }
 
@@ -30,29 +52,321 @@ pub async fn get_version(client: &mongodb::Client) -> mongodb::error::Result<Str
     Ok(String::from(version))
 }
 
-/// get a cursor to all the document ids in a given range (using a given index)
+#[derive(Debug, Deserialize)]
+struct CursorReply {
+    cursor: CursorBody,
+}
+
+#[derive(Debug, Deserialize)]
+struct CursorBody {
+    id: i64,
+    ns: String,
+    #[serde(rename = "firstBatch", alias = "nextBatch")]
+    batch: VecDeque<bson::Document>,
+    #[serde(rename = "atClusterTime")]
+    at_cluster_time: Option<bson::Timestamp>,
+}
+
+/// A cluster time reads can be pinned to, along with the signed cluster time seen alongside it
+///
+/// the signed cluster time is gossiped to shards before reading so they accept an atClusterTime they have not seen yet
+#[derive(Debug, Clone)]
+pub struct SnapshotTime {
+    pub at: bson::Timestamp,
+    pub cluster_time: Option<mongodb::ClusterTime>,
+}
+
+/// A cursor opened by a raw find command and iterated with getMore in its own session
+///
+/// unlike the driver's cursor it can be killed explicitly (and awaited), so interrupted scans do not leave cursors
+/// open on the server. one dropped before being exhausted or killed is killed in the background
+pub struct CommandCursor<T> {
+    /// only taken when the cursor is dropped, to kill it in the background
+    session: Option<mongodb::ClientSession>,
+    database: mongodb::Database,
+    collection: String,
+    id: i64,
+    batch: VecDeque<bson::Document>,
+    /// the member the cursor was opened on, getMores must be sent to the same one
+    selection_criteria: Option<SelectionCriteria>,
+    _type: PhantomData<T>,
+}
+
+impl<T: DeserializeOwned> CommandCursor<T> {
+    /// get the next document, fetching another batch from the server if needed
+    pub async fn try_next(&mut self) -> mongodb::error::Result<Option<T>> {
+        loop {
+            if let Some(doc) = self.batch.pop_front() {
+                return Ok(Some(bson::from_document(doc)?));
+            }
+            if self.id == 0 {
+                return Ok(None);
+            }
+            let get_more = bson::doc! { "getMore": self.id, "collection": &self.collection };
+            let session = self
+                .session
+                .as_mut()
+                .expect("cursor session is only taken on drop");
+            let response = self
+                .database
+                .run_command_with_session(get_more, self.selection_criteria.clone(), session)
+                .await?;
+            let reply: CursorReply = bson::from_document(response)?;
+            self.id = reply.cursor.id;
+            self.batch = reply.cursor.batch;
+        }
+    }
+
+    /// kill the cursor on the server if it is not exhausted yet
+    pub async fn kill(mut self) {
+        if let Some(session) = self.session.as_mut() {
+            kill_cursor(
+                &self.database,
+                &self.collection,
+                self.id,
+                self.selection_criteria.clone(),
+                session,
+            )
+            .await;
+        }
+        self.id = 0;
+    }
+}
+
+impl<T> Drop for CommandCursor<T> {
+    fn drop(&mut self) {
+        if self.id == 0 {
+            return;
+        }
+        let (Some(mut session), Ok(runtime)) =
+            (self.session.take(), tokio::runtime::Handle::try_current())
+        else {
+            return;
+        };
+        let database = self.database.clone();
+        let collection = std::mem::take(&mut self.collection);
+        let id = self.id;
+        let selection_criteria = self.selection_criteria.take();
+        runtime.spawn(async move {
+            kill_cursor(&database, &collection, id, selection_criteria, &mut session).await
+        });
+    }
+}
+
+/// send killCursors for a cursor that is not exhausted, in the session it was opened in
+async fn kill_cursor(
+    database: &mongodb::Database,
+    collection: &str,
+    id: i64,
+    selection_criteria: Option<SelectionCriteria>,
+    session: &mut mongodb::ClientSession,
+) {
+    if id == 0 {
+        return;
+    }
+    let kill = bson::doc! { "killCursors": collection, "cursors": [id] };
+    match database
+        .run_command_with_session(kill, selection_criteria, session)
+        .await
+    {
+        Ok(_) => log::debug!("killed cursor {} on {}", id, collection),
+        Err(err) => log::warn!("cannot kill cursor {}: {}", id, err),
+    }
+}
+
+/// run a raw find command and return a cursor over its results, for reads that need options the driver's find does
+/// not expose (such as a snapshot read at a given cluster time)
+///
+/// returns the cluster time the server read at, if it was a snapshot read, along with the cursor
+pub async fn find_command<T>(
+    client: &mongodb::Client,
+    db: &str,
+    command: bson::Document,
+    gossip: Option<&mongodb::ClusterTime>,
+    selection_criteria: Option<SelectionCriteria>,
+) -> mongodb::error::Result<(Option<SnapshotTime>, CommandCursor<T>)> {
+    let session_options = mongodb::options::SessionOptions::builder()
+        .causal_consistency(false)
+        .build();
+    let mut session = client.start_session(session_options).await?;
+    if let Some(cluster_time) = gossip {
+        session.advance_cluster_time(cluster_time);
+    }
+    let database = client.database(db);
+    let response = database
+        .run_command_with_session(command, selection_criteria.clone(), &mut session)
+        .await?;
+    let reply: CursorReply = bson::from_document(response)?;
+    let snapshot_time = reply.cursor.at_cluster_time.map(|at| SnapshotTime {
+        at,
+        cluster_time: session.cluster_time().cloned(),
+    });
+    let collection = match reply.cursor.ns.split_once('.') {
+        Some((_, collection)) => collection.to_string(),
+        None => reply.cursor.ns.clone(),
+    };
+    let cursor = CommandCursor {
+        session: Some(session),
+        database,
+        collection,
+        id: reply.cursor.id,
+        batch: reply.cursor.batch,
+        selection_criteria,
+        _type: PhantomData,
+    };
+    Ok((snapshot_time, cursor))
+}
+
+/// read concern for a snapshot read, optionally pinned at a cluster time
+pub fn snapshot_read_concern(at_cluster_time: Option<bson::Timestamp>) -> bson::Document {
+    match at_cluster_time {
+        Some(ts) => bson::doc! { "level": "snapshot", "atClusterTime": ts },
+        None => bson::doc! { "level": "snapshot" },
+    }
+}
+
+/// get a cursor to all the document ids in a given shard key range, read as the shard's range scan says
+///
+/// if a cluster time is provided, the range is read from a snapshot at that time, and if the shard's reads are pinned
+/// the range is read from the pinned member rather than its primary, choosing a member again and retrying once if the
+/// pinned one cannot be selected
+#[tracing::instrument(name = "find_id_range", skip_all, fields(min = %min, max = %max))]
+#[allow(clippy::too_many_arguments)]
 pub async fn find_id_range(
     client: &mongodb::Client,
     ns: &mongodb::Namespace,
-    hint: &bson::Document,
+    shard_key: &bson::Document,
+    scan: &RangeScan,
     min: &bson::Document,
     max: &bson::Document,
-) -> mongodb::Cursor<Id> {
-    let index_hint = mongodb::options::Hint::Keys(hint.to_owned());
-    let projection = bson::doc! { "_id": 1 };
+    snapshot_time: Option<&SnapshotTime>,
+    pinned: Option<&PinnedRead>,
+) -> mongodb::error::Result<CommandCursor<Id>> {
+    let mut command = bson::doc! {
+        "find": &ns.coll,
+        "projection": { "_id": 1 },
+    };
+    scan.apply(&mut command, shard_key, min, max);
+    if let Some(snapshot_time) = snapshot_time {
+        command.insert("readConcern", snapshot_read_concern(Some(snapshot_time.at)));
+    }
+    let gossip = snapshot_time.and_then(|time| time.cluster_time.as_ref());
+    let Some(pinned) = pinned else {
+        let (_, cursor) = find_command(client, &ns.db, command, gossip, None).await?;
+        return Ok(cursor);
+    };
+    let address = pinned.address();
+    let criteria = Some(pinned.criteria());
+    match find_command(client, &ns.db, command.clone(), gossip, criteria).await {
+        Ok((_, cursor)) => Ok(cursor),
+        Err(err) if util::is_server_selection_error(&err) => {
+            pinned.repin(&address).await?;
+            let criteria = Some(pinned.criteria());
+            let (_, cursor) = find_command(client, &ns.db, command, gossip, criteria).await?;
+            Ok(cursor)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// list the indexes of a collection, or none if the collection does not exist
+pub async fn list_indexes(
+    client: &mongodb::Client,
+    ns: &mongodb::Namespace,
+) -> mongodb::error::Result<Option<Vec<bson::Document>>> {
+    let command = bson::doc! { "listIndexes": &ns.coll };
+    match find_command::<bson::Document>(client, &ns.db, command, None, None).await {
+        Ok((_, mut cursor)) => {
+            let mut indexes = Vec::new();
+            while let Some(index) = cursor.try_next().await? {
+                indexes.push(index);
+            }
+            Ok(Some(indexes))
+        }
+        Err(err) if util::is_namespace_not_found_error(&err) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// get a collection's listCollections entry (its options and info, including its uuid), or none if the collection does
+/// not exist
+pub async fn list_collection(
+    client: &mongodb::Client,
+    ns: &mongodb::Namespace,
+) -> mongodb::error::Result<Option<bson::Document>> {
+    let command = bson::doc! { "listCollections": 1, "filter": { "name": &ns.coll } };
+    let (_, mut cursor) =
+        find_command::<bson::Document>(client, &ns.db, command, None, None).await?;
+    cursor.try_next().await
+}
+
+/// get a cursor to the ids of every document matching a filter
+pub async fn find_ids(
+    client: &mongodb::Client,
+    ns: &mongodb::Namespace,
+    filter: bson::Document,
+) -> mongodb::error::Result<mongodb::Cursor<Id>> {
     let options = mongodb::options::FindOptions::builder()
-        .min(min.to_owned())
-        .max(max.to_owned())
-        .hint(index_hint)
-        .projection(projection)
+        .projection(bson::doc! { "_id": 1 })
         .build();
-    let result = client
+    client
         .database(&ns.db)
         .collection::<Id>(&ns.coll)
-        .find(None, options)
+        .find(filter, options)
+        .await
+}
+
+/// get a full document by its _id, if it exists
+pub async fn find_by_id(
+    client: &mongodb::Client,
+    ns: &mongodb::Namespace,
+    id: &bson::oid::ObjectId,
+    selection_criteria: Option<SelectionCriteria>,
+) -> mongodb::error::Result<Option<bson::Document>> {
+    let options = mongodb::options::FindOneOptions::builder()
+        .selection_criteria(selection_criteria)
+        .build();
+    client
+        .database(&ns.db)
+        .collection::<bson::Document>(&ns.coll)
+        .find_one(bson::doc! { "_id": id }, options)
         .await
-        .expect("cannot execute find");
-    result
+}
+
+/// insert a single document, returning the error as-is so callers can inspect duplicate keys
+pub async fn insert(
+    client: &mongodb::Client,
+    ns: &mongodb::Namespace,
+    doc: &bson::Document,
+    write_concern: Option<mongodb::options::WriteConcern>,
+) -> mongodb::error::Result<()> {
+    let options = mongodb::options::InsertOneOptions::builder()
+        .write_concern(write_concern)
+        .build();
+    client
+        .database(&ns.db)
+        .collection::<bson::Document>(&ns.coll)
+        .insert_one(doc, options)
+        .await?;
+    Ok(())
+}
+
+/// delete a single document by its _id, returning the number of documents deleted
+pub async fn delete_by_id(
+    client: &mongodb::Client,
+    ns: &mongodb::Namespace,
+    id: &bson::oid::ObjectId,
+    write_concern: Option<mongodb::options::WriteConcern>,
+) -> mongodb::error::Result<u64> {
+    let options = mongodb::options::DeleteOptions::builder()
+        .write_concern(write_concern)
+        .build();
+    let result = client
+        .database(&ns.db)
+        .collection::<bson::Document>(&ns.coll)
+        .delete_one(bson::doc! { "_id": id }, options)
+        .await?;
+    Ok(result.deleted_count)
 }
 
 /// Either estimate or actually count the number of documents in a namespace
@@ -61,30 +375,36 @@ pub async fn count(
     ns: &mongodb::Namespace,
     estimated: bool,
 ) -> mongodb::error::Result<u64> {
-    if estimated == true {
-        return Ok(client
+    if estimated {
+        return client
             .database(ns.db.as_str())
             .collection::<()>(ns.coll.as_str())
             .estimated_document_count(None)
-            .await?);
+            .await;
     }
-    Ok(client
+    client
         .database(ns.db.as_str())
         .collection::<()>(ns.coll.as_str())
         .count_documents(None, None)
-        .await?)
+        .await
 }
 
 pub mod mongos {
-    use std::collections::HashMap;
+    use std::collections::{HashMap, HashSet};
 
     use futures::{future::join_all, TryStreamExt};
     use mongodb::bson;
     use serde::{Deserialize, Serialize};
+    use tracing::Instrument;
 
-    use crate::{chunk::Chunk, util};
+    use crate::{
+        chunk::Chunk,
+        host_map::HostMap,
+        shard_config::{ShardConfig, ShardSettings},
+        util,
+    };
 
-    use super::connect;
+    use super::{connect_with, CommandCursor, SnapshotTime};
 
     const SHARD_STEADY_STATE: usize = 1;
 
@@ -98,62 +418,223 @@ pub mod mongos {
     /// connect to all shards of a given mongos client, returning a hashmap of shard names to clients
     ///
     /// attempts to connect to shards in parallel
+    #[tracing::instrument(skip_all)]
     pub async fn connect_to_shards(
         mongos: &mongodb::Client,
-        uri: &str,
+        router_options: &mongodb::options::ClientOptions,
+        host_map: &HostMap,
+        shard_config: &ShardConfig,
     ) -> mongodb::error::Result<HashMap<String, mongodb::Client>> {
-        assert_mongos(&mongos).await?;
+        assert_mongos(mongos).await?;
 
         let mut shard_names = Vec::new();
         let mut tasks = Vec::new();
 
-        let mut shards_cursor = mongos
+        let shards = mongos
             .database("config")
             .collection::<ShardDoc>("shards")
             .find(None, None)
+            .await?
+            .try_collect::<Vec<_>>()
             .await?;
-        while let Some(shard) = shards_cursor.try_next().await? {
+        shard_config
+            .check_names(shards.iter().map(|shard| shard._id.as_str()))
+            .map_err(std::io::Error::other)?;
+        for shard in shards {
             if shard.state != SHARD_STEADY_STATE {
                 log::warn!("skipping shard {}, is not in a steady state", shard._id);
                 continue;
             }
-            let updated_uri = util::update_connection_string(uri, shard.host.as_str());
+            let span = tracing::info_span!("connect", shard = %shard._id);
+            let settings = shard_config.get(&shard._id).cloned();
+            let task = match host_map.remap(&shard.host) {
+                Some(hosts) => {
+                    let options = hosts
+                        .iter()
+                        .map(|host| util::direct_options(router_options, &shard.host, host))
+                        .collect::<mongodb::error::Result<Vec<_>>>()?;
+                    tokio::spawn(
+                        async move { connect_to_primary(options, settings.as_ref()).await }
+                            .instrument(span),
+                    )
+                }
+                None => {
+                    let options = util::shard_options(router_options, shard.host.as_str())?;
+                    tokio::spawn(
+                        async move {
+                            log::debug!("Connecting to {}", &shard.host);
+                            connect_with(options, settings.as_ref()).await
+                        }
+                        .instrument(span),
+                    )
+                }
+            };
 
             // maintaining two separate ordered lists so clients will be connected to in parallel tasks
+            tasks.push(task);
             shard_names.push(shard._id);
-            tasks.push(tokio::spawn(async move {
-                log::debug!("Connecting to {}", &updated_uri);
-                connect(updated_uri.as_str()).await.unwrap()
-            }));
         }
 
-        // wait for all tasks to finish, panics if any connection issue to any of the shards
+        // wait for all tasks to finish, failing if any of the shards cannot be connected to
         // note that join_all is guarenteed to return in the provided order so no concern zipping arrays back up
-        let connected_client = join_all(tasks)
-            .await
-            .into_iter()
-            .map(|x| x.unwrap())
-            .collect::<Vec<mongodb::Client>>();
-
-        let zipped = shard_names.into_iter().zip(connected_client.into_iter());
-        let shard_map = HashMap::from_iter(zipped);
+        let mut shard_map = HashMap::new();
+        for (shard_name, result) in shard_names.into_iter().zip(join_all(tasks).await) {
+            let client = result
+                .map_err(|err| {
+                    std::io::Error::other(format!(
+                        "connecting to shard {} failed: {}",
+                        shard_name, err
+                    ))
+                })?
+                .map_err(|err| {
+                    log::error!("cannot connect to shard {}: {}", shard_name, err);
+                    err
+                })?;
+            shard_map.insert(shard_name, client);
+        }
         Ok(shard_map)
     }
 
+    /// return the name of every shard in config.shards, whatever its state
+    pub async fn get_shard_names(
+        mongos: &mongodb::Client,
+    ) -> mongodb::error::Result<HashSet<String>> {
+        let mut names = HashSet::new();
+        let mut shards_cursor = mongos
+            .database("config")
+            .collection::<ShardDoc>("shards")
+            .find(None, None)
+            .await?;
+        while let Some(shard) = shards_cursor.try_next().await? {
+            names.insert(shard._id);
+        }
+        Ok(names)
+    }
+
+    /// connect directly to each remapped member of a shard in turn, returning the primary, or the first member that
+    /// could be reached if none of the mapped members is primary (enough for scans, but not for writes)
+    async fn connect_to_primary(
+        members: Vec<mongodb::options::ClientOptions>,
+        settings: Option<&ShardSettings>,
+    ) -> mongodb::error::Result<mongodb::Client> {
+        let mut fallback = None;
+        for options in members {
+            log::debug!("Connecting directly to {:?}", &options.hosts);
+            let client = match connect_with(options, settings).await {
+                Ok(client) => client,
+                Err(err) => {
+                    log::warn!("cannot connect to remapped shard member: {}", err);
+                    continue;
+                }
+            };
+            let hello = client
+                .database("admin")
+                .run_command(bson::doc! { "isMaster": 1 }, None)
+                .await?;
+            if hello.get_bool("ismaster").unwrap_or(false) {
+                return Ok(client);
+            }
+            fallback.get_or_insert(client);
+        }
+        match fallback {
+            Some(client) => {
+                log::warn!(
+                    "none of the remapped members of the shard is primary, writes to it will fail"
+                );
+                Ok(client)
+            }
+            None => {
+                Err(std::io::Error::other("cannot reach any remapped member of the shard").into())
+            }
+        }
+    }
+
     /// return a cursor to the chunks collection, optionally with a filter
     pub async fn get_chunk_cursor(
         mongos: &mongodb::Client,
         filter: Option<bson::Document>,
-    ) -> mongodb::error::Result<mongodb::Cursor<Chunk>> {
-        assert_mongos(&mongos)
-            .await
-            .expect("problem checking mongos");
-        let cursor = mongos
+    ) -> mongodb::error::Result<CommandCursor<Chunk>> {
+        assert_mongos(mongos).await?;
+        let command = bson::doc! { "find": "chunks", "filter": filter.unwrap_or_default() };
+        let (_, cursor) = super::find_command(mongos, "config", command, None, None).await?;
+        Ok(cursor)
+    }
+
+    /// return a cursor to the chunks collection read from a snapshot, along with the cluster time the snapshot was
+    /// taken at
+    pub async fn get_chunk_snapshot(
+        mongos: &mongodb::Client,
+        filter: bson::Document,
+    ) -> mongodb::error::Result<(Option<SnapshotTime>, CommandCursor<Chunk>)> {
+        assert_mongos(mongos).await?;
+        let command = bson::doc! {
+            "find": "chunks",
+            "filter": filter,
+            "readConcern": super::snapshot_read_concern(None),
+        };
+        super::find_command(mongos, "config", command, None, None).await
+    }
+
+    /// count the chunks matching a filter
+    pub async fn count_chunks(
+        mongos: &mongodb::Client,
+        filter: &bson::Document,
+    ) -> mongodb::error::Result<u64> {
+        mongos
             .database("config")
             .collection::<Chunk>("chunks")
-            .find(filter, None)
+            .count_documents(filter.clone(), None)
+            .await
+    }
+
+    /// return the number of chunks each shard owns
+    pub async fn count_chunks_by_shard(
+        mongos: &mongodb::Client,
+        filter: &bson::Document,
+    ) -> mongodb::error::Result<HashMap<String, u64>> {
+        let pipeline = vec![
+            bson::doc! { "$match": filter },
+            bson::doc! { "$group": { "_id": "$shard", "chunks": { "$sum": 1 } } },
+        ];
+        let mut cursor = mongos
+            .database("config")
+            .collection::<bson::Document>("chunks")
+            .aggregate(pipeline, None)
             .await?;
-        Ok(cursor)
+        let mut counts = HashMap::new();
+        while let Some(doc) = cursor.try_next().await? {
+            if let Ok(shard) = doc.get_str("_id") {
+                let chunks = doc
+                    .get_i32("chunks")
+                    .map(i64::from)
+                    .or_else(|_| doc.get_i64("chunks"))
+                    .unwrap_or(0);
+                counts.insert(shard.to_string(), chunks as u64);
+            }
+        }
+        Ok(counts)
+    }
+
+    /// return every chunk overlapping the range [min, max), sorted by min
+    pub async fn get_chunks_in_range(
+        mongos: &mongodb::Client,
+        ns_filter: &bson::Document,
+        min: &bson::Document,
+        max: &bson::Document,
+    ) -> mongodb::error::Result<Vec<Chunk>> {
+        let mut filter = ns_filter.clone();
+        filter.insert("min", bson::doc! { "$lt": max });
+        filter.insert("max", bson::doc! { "$gt": min });
+        let options = mongodb::options::FindOptions::builder()
+            .sort(bson::doc! { "min": 1 })
+            .build();
+        mongos
+            .database("config")
+            .collection::<Chunk>("chunks")
+            .find(filter, options)
+            .await?
+            .try_collect()
+            .await
     }
 
     /// returns true if connected client is pointed at mongos process, false if not. panics if error is not related to isdbgrid
@@ -171,21 +652,44 @@ pub mod mongos {
         true
     }
 
-    /// get a document for the shard key used to shard a collection
+    /// get a document for the shard key used to shard a collection, or none if the collection is not sharded
     pub async fn get_shard_key(
         mongos: &mongodb::Client,
         ns: &mongodb::Namespace,
-    ) -> mongodb::error::Result<bson::Document> {
-        assert_mongos(&mongos).await?;
+    ) -> mongodb::error::Result<Option<bson::Document>> {
+        assert_mongos(mongos).await?;
+
+        let doc = match get_collection(mongos, ns).await? {
+            Some(doc) if !doc.get_bool("dropped").unwrap_or(false) => doc,
+            _ => return Ok(None),
+        };
+        Ok(doc.get_document("key").ok().cloned())
+    }
 
-        let filter = bson::doc! { "_id": ns.to_string() };
+    /// get the name of a database's primary shard, which holds its unsharded collections, or none if the database
+    /// does not exist
+    pub async fn get_primary_shard(
+        mongos: &mongodb::Client,
+        db: &str,
+    ) -> mongodb::error::Result<Option<String>> {
         let doc = mongos
+            .database("config")
+            .collection::<bson::Document>("databases")
+            .find_one(bson::doc! { "_id": db }, None)
+            .await?;
+        Ok(doc.and_then(|doc| doc.get_str("primary").ok().map(String::from)))
+    }
+
+    /// return the namespace's config.collections document, or none if it was never sharded
+    pub async fn get_collection(
+        mongos: &mongodb::Client,
+        ns: &mongodb::Namespace,
+    ) -> mongodb::error::Result<Option<bson::Document>> {
+        mongos
             .database("config")
             .collection::<bson::Document>("collections")
-            .find_one(filter, None)
-            .await?
-            .unwrap();
-        Ok(doc.get("key").unwrap().as_document().unwrap().to_owned())
+            .find_one(bson::doc! { "_id": ns.to_string() }, None)
+            .await
     }
 
     /// exits the program with an error if client is not one or more mongos process
@@ -0,0 +1,164 @@
+use std::fmt;
+
+use mongodb::bson;
+
+use crate::orphan::Orphan;
+
+/// Field-level differences between an orphan and the live document with the same _id on its owning shard
+///
+/// Paths are dotted (array elements are addressed by index) and are relative to the live document, e.g. `added`
+/// holds paths that only exist on the orphan
+#[derive(Debug, Default, PartialEq)]
+pub struct DocumentDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub changed: Vec<String>,
+}
+
+impl DocumentDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+impl fmt::Display for DocumentDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "added: {:?}, removed: {:?}, changed: {:?}",
+            self.added, self.removed, self.changed
+        )
+    }
+}
+
+/// Results of comparing each orphan against its owning shard
+///
+/// Orphans are unique when the owning shard has no document with the same _id, identical when it has an equal
+/// document, and divergent otherwise
+#[derive(Debug, Default)]
+pub struct DiffReport {
+    pub unique: Vec<Orphan>,
+    pub identical: Vec<Orphan>,
+    pub divergent: Vec<(Orphan, DocumentDiff)>,
+}
+
+impl DiffReport {
+    pub fn add(
+        &mut self,
+        orphan: Orphan,
+        orphan_doc: &bson::Document,
+        owned: Option<&bson::Document>,
+    ) {
+        match owned {
+            None => self.unique.push(orphan),
+            Some(owned) => {
+                let diff = diff_documents(orphan_doc, owned);
+                if diff.is_empty() {
+                    self.identical.push(orphan);
+                } else {
+                    self.divergent.push((orphan, diff));
+                }
+            }
+        }
+    }
+}
+
+/// compare an orphaned document against the live document, returning every path that was added, removed or changed
+pub fn diff_documents(orphan: &bson::Document, owned: &bson::Document) -> DocumentDiff {
+    let mut diff = DocumentDiff::default();
+    diff_document_at("", orphan, owned, &mut diff);
+    diff
+}
+
+fn join_path(prefix: &str, key: &str) -> String {
+    if prefix.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", prefix, key)
+    }
+}
+
+fn diff_document_at(
+    prefix: &str,
+    orphan: &bson::Document,
+    owned: &bson::Document,
+    diff: &mut DocumentDiff,
+) {
+    for (key, value) in orphan.iter() {
+        let path = join_path(prefix, key);
+        match owned.get(key) {
+            Some(other) => diff_value_at(&path, value, other, diff),
+            None => diff.added.push(path),
+        }
+    }
+    for key in owned.keys() {
+        if !orphan.contains_key(key) {
+            diff.removed.push(join_path(prefix, key));
+        }
+    }
+}
+
+fn diff_value_at(path: &str, orphan: &bson::Bson, owned: &bson::Bson, diff: &mut DocumentDiff) {
+    match (orphan, owned) {
+        (bson::Bson::Document(orphan), bson::Bson::Document(owned)) => {
+            diff_document_at(path, orphan, owned, diff)
+        }
+        (bson::Bson::Array(orphan), bson::Bson::Array(owned)) => {
+            for (i, value) in orphan.iter().enumerate() {
+                let element = join_path(path, &i.to_string());
+                match owned.get(i) {
+                    Some(other) => diff_value_at(&element, value, other, diff),
+                    None => diff.added.push(element),
+                }
+            }
+            for i in orphan.len()..owned.len() {
+                diff.removed.push(join_path(path, &i.to_string()));
+            }
+        }
+        _ => {
+            if orphan != owned {
+                diff.changed.push(path.to_string());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::doc;
+
+    #[test]
+    fn identical_documents_have_no_diff() {
+        let doc = doc! { "_id": 1, "a": { "b": [1, 2] } };
+        let diff = super::diff_documents(&doc, &doc.clone());
+        assert!(diff.is_empty());
+    }
+
+    #[test]
+    fn diff_top_level_fields() {
+        let orphan = doc! { "_id": 1, "a": 1, "b": "x" };
+        let owned = doc! { "_id": 1, "a": 2, "c": true };
+        let diff = super::diff_documents(&orphan, &owned);
+        assert_eq!(vec!["b"], diff.added);
+        assert_eq!(vec!["c"], diff.removed);
+        assert_eq!(vec!["a"], diff.changed);
+    }
+
+    #[test]
+    fn diff_nested_fields_and_arrays() {
+        let orphan = doc! { "_id": 1, "a": { "b": 1, "c": 1 }, "d": [1, 2, 3] };
+        let owned = doc! { "_id": 1, "a": { "b": 2 }, "d": [1, 5] };
+        let diff = super::diff_documents(&orphan, &owned);
+        assert_eq!(vec!["a.c", "d.2"], diff.added);
+        assert!(diff.removed.is_empty());
+        assert_eq!(vec!["a.b", "d.1"], diff.changed);
+    }
+
+    #[test]
+    fn diff_type_change_is_a_change() {
+        let orphan = doc! { "_id": 1, "a": { "b": 1 } };
+        let owned = doc! { "_id": 1, "a": 1 };
+        let diff = super::diff_documents(&orphan, &owned);
+        assert_eq!(vec!["a"], diff.changed);
+    }
+}
@@ -0,0 +1,493 @@
+use std::{
+    collections::HashSet,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use mongodb::{
+    bson::{self, Bson, Document},
+    options::SelectionCriteria,
+};
+use serde::Deserialize;
+
+use crate::shutdown::Shutdown;
+
+/// longest pause between two dispatches to a shard that is close to its thresholds
+const MAX_DELAY: Duration = Duration::from_secs(1);
+
+/// how often a paused dispatch re-checks the shard's health
+const PAUSE_POLL: Duration = Duration::from_millis(500);
+
+/// replica set member states, see https://www.mongodb.com/docs/manual/reference/replica-states/
+const PRIMARY: i32 = 1;
+const SECONDARY: i32 = 2;
+
+/// Limits above which a shard is considered struggling and dispatch to it is paused
+#[derive(Debug, Clone)]
+pub struct Thresholds {
+    /// operations queued waiting for the global lock / storage engine tickets
+    pub max_queued_ops: u64,
+    /// fraction of the WiredTiger cache in use
+    pub max_cache_used: f64,
+    /// fraction of the WiredTiger cache holding dirty data
+    pub max_cache_dirty: f64,
+    /// how far the slowest healthy secondary is behind the primary, delayed and hidden members aside
+    pub max_replication_lag: Duration,
+}
+
+/// How shards are sampled and what counts as struggling
+#[derive(Debug, Clone)]
+pub struct HealthOptions {
+    pub interval: Duration,
+    pub thresholds: Thresholds,
+    /// longest a single dispatch waits on a paused shard before failing
+    pub max_pause: Duration,
+}
+
+/// Load indicators read from a shard member's serverStatus and replSetGetStatus
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Sample {
+    pub queued_ops: u64,
+    pub cache_used: f64,
+    pub cache_dirty: f64,
+    pub replication_lag: Option<Duration>,
+}
+
+impl Sample {
+    /// highest ratio of any indicator to its threshold, 1.0 or above means the shard is over a threshold
+    pub fn pressure(&self, thresholds: &Thresholds) -> f64 {
+        let lag = self
+            .replication_lag
+            .map(|lag| {
+                ratio(
+                    lag.as_secs_f64(),
+                    thresholds.max_replication_lag.as_secs_f64(),
+                )
+            })
+            .unwrap_or_default();
+        [
+            ratio(self.queued_ops as f64, thresholds.max_queued_ops as f64),
+            ratio(self.cache_used, thresholds.max_cache_used),
+            ratio(self.cache_dirty, thresholds.max_cache_dirty),
+            lag,
+        ]
+        .into_iter()
+        .fold(0.0, f64::max)
+    }
+}
+
+fn ratio(value: f64, limit: f64) -> f64 {
+    if limit > 0.0 {
+        value / limit
+    } else {
+        0.0
+    }
+}
+
+/// What dispatch to a shard should do at a given pressure
+#[derive(Debug, PartialEq)]
+pub enum Pace {
+    /// dispatch immediately
+    Full,
+    /// space dispatches out by the given delay, growing as the shard gets closer to a threshold
+    Slow(Duration),
+    /// stop dispatching until the shard recovers
+    Paused,
+}
+
+impl Pace {
+    /// below half of every threshold run at full speed, then slow down linearly until pausing at the threshold
+    pub fn for_pressure(pressure: f64) -> Self {
+        if pressure >= 1.0 {
+            Pace::Paused
+        } else if pressure <= 0.5 {
+            Pace::Full
+        } else {
+            Pace::Slow(MAX_DELAY.mul_f64((pressure - 0.5) * 2.0))
+        }
+    }
+}
+
+/// Dispatch gate for a single shard, updated by a background monitor and awaited by tasks before they query the shard
+#[derive(Debug)]
+pub struct Throttle {
+    shard: String,
+    max_pause: Duration,
+    paused: AtomicBool,
+    delay_ms: AtomicU64,
+    /// serializes dispatch so the delay spaces out queries rather than just postponing all of them
+    dispatch: tokio::sync::Mutex<()>,
+}
+
+impl Throttle {
+    fn new(shard: String, max_pause: Duration) -> Self {
+        Throttle {
+            shard,
+            max_pause,
+            paused: AtomicBool::new(false),
+            delay_ms: AtomicU64::new(0),
+            dispatch: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    fn set(&self, pace: &Pace) {
+        let (paused, delay) = match pace {
+            Pace::Full => (false, Duration::ZERO),
+            Pace::Slow(delay) => (false, *delay),
+            Pace::Paused => (true, Duration::ZERO),
+        };
+        self.paused.store(paused, Ordering::Relaxed);
+        self.delay_ms
+            .store(delay.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    /// wait until the shard may be queried, returning false as soon as shutdown is triggered so the caller does not
+    /// query it, or an error if the shard stays paused for longer than the maximum pause
+    pub async fn wait(&self, shutdown: &Shutdown) -> mongodb::error::Result<bool> {
+        let _dispatch = self.dispatch.lock().await;
+        let paused_since = Instant::now();
+        while self.paused.load(Ordering::Relaxed) {
+            if shutdown.is_triggered() {
+                return Ok(false);
+            }
+            if paused_since.elapsed() >= self.max_pause {
+                return Err(std::io::Error::other(format!(
+                    "dispatch to shard {} has been paused over a health threshold for {}s, giving up",
+                    self.shard,
+                    self.max_pause.as_secs()
+                ))
+                .into());
+            }
+            tokio::time::sleep(PAUSE_POLL).await;
+        }
+        let delay = self.delay_ms.load(Ordering::Relaxed);
+        if delay > 0 {
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+        }
+        Ok(!shutdown.is_triggered())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ReplSetStatus {
+    members: Vec<Member>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Member {
+    #[serde(rename = "_id")]
+    id: i32,
+    state: i32,
+    #[serde(default)]
+    health: f64,
+    #[serde(rename = "optimeDate")]
+    optime_date: bson::DateTime,
+}
+
+#[derive(Debug, Deserialize)]
+struct ReplSetConfigReply {
+    config: ReplSetConfig,
+}
+
+#[derive(Debug, Deserialize)]
+struct ReplSetConfig {
+    members: Vec<MemberConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+struct MemberConfig {
+    #[serde(rename = "_id")]
+    id: i32,
+    #[serde(default)]
+    hidden: bool,
+    /// named slaveDelay before 5.0
+    #[serde(rename = "secondaryDelaySecs", alias = "slaveDelay", default)]
+    delay_secs: i64,
+}
+
+/// ids of the members whose lag is not held against the shard -- delayed members lag by design and hidden ones
+/// serve no reads, so either could keep the shard paused for good
+fn lag_exempt(members: &[MemberConfig]) -> HashSet<i32> {
+    members
+        .iter()
+        .filter(|member| member.hidden || member.delay_secs > 0)
+        .map(|member| member.id)
+        .collect()
+}
+
+/// lag of the slowest healthy secondary behind the primary, if there is both a primary and a healthy secondary that
+/// is not exempt
+fn replication_lag(members: &[Member], exempt: &HashSet<i32>) -> Option<Duration> {
+    let primary = members.iter().find(|member| member.state == PRIMARY)?;
+    members
+        .iter()
+        .filter(|member| {
+            member.state == SECONDARY && member.health > 0.0 && !exempt.contains(&member.id)
+        })
+        .map(|member| {
+            let behind =
+                primary.optime_date.timestamp_millis() - member.optime_date.timestamp_millis();
+            Duration::from_millis(behind.max(0) as u64)
+        })
+        .max()
+}
+
+/// read a numeric field from a nested path, whatever numeric type the server reported it as
+fn number(doc: &Document, path: &[&str]) -> Option<f64> {
+    let (last, parents) = path.split_last()?;
+    let mut doc = doc;
+    for key in parents {
+        doc = doc.get_document(key).ok()?;
+    }
+    match doc.get(last)? {
+        Bson::Int32(n) => Some(*n as f64),
+        Bson::Int64(n) => Some(*n as f64),
+        Bson::Double(n) => Some(*n),
+        _ => None,
+    }
+}
+
+fn parse_server_status(status: &Document) -> Sample {
+    let cache = |field| number(status, &["wiredTiger", "cache", field]);
+    let max = cache("maximum bytes configured").unwrap_or_default();
+    Sample {
+        queued_ops: number(status, &["globalLock", "currentQueue", "total"]).unwrap_or_default()
+            as u64,
+        cache_used: ratio(
+            cache("bytes currently in the cache").unwrap_or_default(),
+            max,
+        ),
+        cache_dirty: ratio(
+            cache("tracked dirty bytes in the cache").unwrap_or_default(),
+            max,
+        ),
+        replication_lag: None,
+    }
+}
+
+/// sample a shard member, its primary unless selection criteria pin another one, replication lag is left unset if
+/// the shard is not a replica set
+pub async fn sample(
+    client: &mongodb::Client,
+    member: Option<&SelectionCriteria>,
+) -> mongodb::error::Result<Sample> {
+    let admin = client.database("admin");
+    let status = admin
+        .run_command(bson::doc! { "serverStatus": 1 }, member.cloned())
+        .await?;
+    let mut sample = parse_server_status(&status);
+    if let Ok(repl) = admin
+        .run_command(bson::doc! { "replSetGetStatus": 1 }, member.cloned())
+        .await
+    {
+        if let Ok(repl) = bson::from_document::<ReplSetStatus>(repl) {
+            let exempt = match admin
+                .run_command(bson::doc! { "replSetGetConfig": 1 }, member.cloned())
+                .await
+                .map(bson::from_document::<ReplSetConfigReply>)
+            {
+                Ok(Ok(reply)) => lag_exempt(&reply.config.members),
+                _ => HashSet::new(),
+            };
+            sample.replication_lag = replication_lag(&repl.members, &exempt);
+        }
+    }
+    Ok(sample)
+}
+
+/// sample every given member of a shard, returning the sample under the most pressure
+async fn sample_worst(
+    client: &mongodb::Client,
+    members: &[Option<SelectionCriteria>],
+    thresholds: &Thresholds,
+) -> mongodb::error::Result<Sample> {
+    let mut worst: Option<Sample> = None;
+    for member in members {
+        let sample = sample(client, member.as_ref()).await?;
+        if worst
+            .as_ref()
+            .is_none_or(|worst| sample.pressure(thresholds) > worst.pressure(thresholds))
+        {
+            worst = Some(sample);
+        }
+    }
+    Ok(worst.unwrap_or_default())
+}
+
+/// sample a shard once and update its throttle, logging when the shard's pace changes between full, slow and paused
+async fn check(
+    shard: &str,
+    client: &mongodb::Client,
+    members: &[Option<SelectionCriteria>],
+    thresholds: &Thresholds,
+    throttle: &Throttle,
+) {
+    let pace = match sample_worst(client, members, thresholds).await {
+        Ok(sample) => {
+            let pace = Pace::for_pressure(sample.pressure(thresholds));
+            log::debug!("shard {} health {:?}, pace {:?}", shard, sample, pace);
+            let was_paused = throttle.paused.load(Ordering::Relaxed);
+            match pace {
+                Pace::Paused if !was_paused => log::warn!(
+                    "shard {} is over a health threshold ({:?}), pausing dispatch",
+                    shard,
+                    sample
+                ),
+                Pace::Full | Pace::Slow(_) if was_paused => {
+                    log::info!("shard {} recovered, resuming dispatch", shard)
+                }
+                _ => {}
+            }
+            pace
+        }
+        Err(err) => {
+            // keep the last known pace rather than stalling or flooding a shard that cannot be sampled
+            log::warn!("cannot sample health of shard {}: {}", shard, err);
+            return;
+        }
+    };
+    throttle.set(&pace);
+}
+
+/// sample the shard once before returning, then keep sampling every interval until the returned handle is aborted
+///
+/// the shard's pace is decided by the worst of the given members, each its primary if none or the member the
+/// selection criteria pin
+pub async fn monitor(
+    shard: String,
+    client: mongodb::Client,
+    members: Vec<Option<SelectionCriteria>>,
+    options: HealthOptions,
+) -> (Arc<Throttle>, tokio::task::JoinHandle<()>) {
+    let throttle = Arc::new(Throttle::new(shard.clone(), options.max_pause));
+    check(&shard, &client, &members, &options.thresholds, &throttle).await;
+    let monitored = throttle.clone();
+    let handle = tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(options.interval);
+        // the first tick completes immediately
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            check(&shard, &client, &members, &options.thresholds, &monitored).await;
+        }
+    });
+    (throttle, handle)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, time::Duration};
+
+    use mongodb::bson::{self, doc, DateTime};
+
+    use super::{Member, Pace, ReplSetConfigReply, Sample, Thresholds};
+
+    fn thresholds() -> Thresholds {
+        Thresholds {
+            max_queued_ops: 100,
+            max_cache_used: 0.9,
+            max_cache_dirty: 0.1,
+            max_replication_lag: Duration::from_secs(10),
+        }
+    }
+
+    fn member(id: i32, state: i32, health: f64, optime_millis: i64) -> Member {
+        Member {
+            id,
+            state,
+            health,
+            optime_date: DateTime::from_millis(optime_millis),
+        }
+    }
+
+    #[test]
+    fn pressure_is_worst_indicator() {
+        let sample = Sample {
+            queued_ops: 10,
+            cache_used: 0.45,
+            cache_dirty: 0.08,
+            replication_lag: Some(Duration::from_secs(2)),
+        };
+        assert!((sample.pressure(&thresholds()) - 0.8).abs() < 1e-9);
+    }
+
+    #[test]
+    fn pace_slows_then_pauses() {
+        assert_eq!(Pace::Full, Pace::for_pressure(0.3));
+        assert_eq!(
+            Pace::Slow(Duration::from_millis(500)),
+            Pace::for_pressure(0.75)
+        );
+        assert_eq!(Pace::Paused, Pace::for_pressure(1.2));
+    }
+
+    #[test]
+    fn lag_of_slowest_healthy_secondary() {
+        let members = vec![
+            member(0, 1, 1.0, 10_000),
+            member(1, 2, 1.0, 9_000),
+            member(2, 2, 1.0, 7_000),
+            // unreachable members report a stale optime
+            member(3, 8, 0.0, 0),
+            member(4, 2, 0.0, 0),
+        ];
+        assert_eq!(
+            Some(Duration::from_secs(3)),
+            super::replication_lag(&members, &HashSet::new())
+        );
+        assert_eq!(None, super::replication_lag(&members[1..], &HashSet::new()));
+    }
+
+    #[test]
+    fn delayed_and_hidden_members_do_not_lag() {
+        let reply = doc! {
+            "config": {
+                "_id": "shard01",
+                "members": [
+                    { "_id": 0, "host": "a:27017", "hidden": false, "secondaryDelaySecs": 0_i64 },
+                    { "_id": 1, "host": "b:27017", "secondaryDelaySecs": 0_i64 },
+                    { "_id": 2, "host": "c:27017", "hidden": true, "priority": 0 },
+                    { "_id": 3, "host": "d:27017", "hidden": true, "slaveDelay": 3600 },
+                ],
+            },
+            "ok": 1,
+        };
+        let config = bson::from_document::<ReplSetConfigReply>(reply).unwrap();
+        let exempt = super::lag_exempt(&config.config.members);
+        assert_eq!(HashSet::from([2, 3]), exempt);
+
+        let members = vec![
+            member(0, 1, 1.0, 10_000_000),
+            member(1, 2, 1.0, 9_999_000),
+            member(2, 2, 1.0, 9_000_000),
+            member(3, 2, 1.0, 6_400_000),
+        ];
+        assert_eq!(
+            Some(Duration::from_secs(1)),
+            super::replication_lag(&members, &exempt)
+        );
+    }
+
+    #[test]
+    fn parse_server_status_numbers() {
+        let status = doc! {
+            "globalLock": { "currentQueue": { "total": 12, "readers": 2, "writers": 10 } },
+            "wiredTiger": { "cache": {
+                "maximum bytes configured": 1000_i64,
+                "bytes currently in the cache": 500.0,
+                "tracked dirty bytes in the cache": 50_i64,
+            } },
+        };
+        let sample = super::parse_server_status(&status);
+        assert_eq!(12, sample.queued_ops);
+        assert_eq!(0.5, sample.cache_used);
+        assert_eq!(0.05, sample.cache_dirty);
+    }
+}
@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+
+/// Mapping of shard hosts as listed in config.shards to addresses reachable from where orphanage runs, e.g. the local
+/// end of an SSH tunnel or a Kubernetes port-forward
+#[derive(Debug, Clone, Default)]
+pub struct HostMap(HashMap<String, String>);
+
+/// parse a single `internal:port=reachable:port` mapping
+pub fn parse_mapping(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((internal, reachable)) if !internal.is_empty() && !reachable.is_empty() => {
+            Ok((internal.to_string(), reachable.to_string()))
+        }
+        _ => Err(format!(
+            "host mapping {} is not of the form internal:port=reachable:port",
+            value
+        )),
+    }
+}
+
+impl HostMap {
+    /// read the `[host_map]` table of `"internal:port" = "reachable:port"` pairs from the config file, if any
+    pub fn from_config(config: &toml::Table) -> Result<Self, String> {
+        match config.get("host_map") {
+            Some(host_map) => host_map
+                .clone()
+                .try_into()
+                .map(HostMap)
+                .map_err(|err| format!("cannot parse [host_map] of config: {}", err)),
+            None => Ok(HostMap::default()),
+        }
+    }
+
+    pub fn insert(&mut self, internal: String, reachable: String) {
+        self.0.insert(internal, reachable);
+    }
+
+    /// reachable addresses for the hosts of a shard (`rs/host:port,...` as in config.shards) that have a mapping, in
+    /// the order they are listed, or none if no host of the shard is mapped
+    pub fn remap(&self, shard_host: &str) -> Option<Vec<String>> {
+        let hosts = shard_host
+            .split_once('/')
+            .map_or(shard_host, |(_, hosts)| hosts);
+        let mapped = hosts
+            .split(',')
+            .filter_map(|host| self.0.get(host).cloned())
+            .collect::<Vec<_>>();
+        (!mapped.is_empty()).then_some(mapped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HostMap;
+
+    fn host_map() -> HostMap {
+        let mut host_map = HostMap::default();
+        host_map.insert(
+            String::from("shard01-a.internal:27017"),
+            String::from("localhost:37017"),
+        );
+        host_map.insert(
+            String::from("shard01-b.internal:27017"),
+            String::from("localhost:37018"),
+        );
+        host_map
+    }
+
+    #[test]
+    fn parse_host_mapping() {
+        assert_eq!(
+            Ok((
+                String::from("shard01-a.internal:27017"),
+                String::from("localhost:37017")
+            )),
+            super::parse_mapping("shard01-a.internal:27017=localhost:37017")
+        );
+        assert!(super::parse_mapping("shard01-a.internal:27017").is_err());
+        assert!(super::parse_mapping("=localhost:37017").is_err());
+    }
+
+    #[test]
+    fn remap_mapped_hosts_in_order() {
+        let remapped = host_map().remap(
+            "shard01/shard01-b.internal:27017,shard01-c.internal:27017,shard01-a.internal:27017",
+        );
+        assert_eq!(
+            Some(vec![
+                String::from("localhost:37018"),
+                String::from("localhost:37017")
+            ]),
+            remapped
+        );
+    }
+
+    #[test]
+    fn host_map_from_config() {
+        let config =
+            toml::from_str("[host_map]\n\"shard01-a.internal:27017\" = \"localhost:37017\"\n")
+                .unwrap();
+        assert_eq!(
+            Some(vec![String::from("localhost:37017")]),
+            HostMap::from_config(&config)
+                .unwrap()
+                .remap("shard01/shard01-a.internal:27017")
+        );
+        let config = toml::from_str("[host_map]\n\"shard01-a.internal:27017\" = 37017\n").unwrap();
+        assert!(HostMap::from_config(&config).is_err());
+    }
+
+    #[test]
+    fn remap_unmapped_shard() {
+        assert_eq!(None, host_map().remap("shard02/shard02-a.internal:27017"));
+    }
+}
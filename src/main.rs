@@ -2,76 +2,470 @@ mod chunk;
 mod cli;
 mod cluster;
 mod db;
+mod diff;
+mod health;
+mod host_map;
+mod marker;
+mod metrics;
 mod orphan;
+mod plan;
+mod preflight;
+mod progress;
+mod read_preference;
+mod routing_table;
+mod shard_collections;
+mod shard_config;
+mod shard_key_index;
+mod shutdown;
+mod telemetry;
 mod util;
+mod write;
+
+use tracing::Instrument;
 
 const BUFFER_SIZE: usize = 100_000;
 
-fn init_logging() {
-    let mut builder = env_logger::Builder::from_default_env();
-    builder.target(env_logger::Target::Stdout);
-    builder.init();
+/// id attached to every log line of a run, the update's run id if one was given so logs correlate with marks
+fn log_run_id(mode: &cli::Mode) -> String {
+    match mode {
+        cli::Mode::Update {
+            run_id: Some(run_id),
+            ..
+        } => run_id.clone(),
+        _ => mongodb::bson::oid::ObjectId::new().to_hex(),
+    }
+}
+
+/// How a mode that ran to completion ended
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Outcome {
+    Clean,
+    /// problems were found or writes failed, reported above
+    Problems,
 }
 
 async fn estimate(
     cluster: cluster::ShardedCluster,
     ns: mongodb::Namespace,
-) -> mongodb::error::Result<()> {
+) -> mongodb::error::Result<Outcome> {
     let estimate = cluster.estimate_orphaned(&ns).await?;
     log::info!("estimated_count: {}", estimate);
-    Ok(())
+    for remnant in cluster.find_remnants(&ns).await? {
+        log::warn!(
+            "shard {} owns no chunks but holds ~{} document(s), the whole collection there is orphaned",
+            remnant.shard,
+            remnant.documents
+        );
+    }
+    Ok(Outcome::Clean)
+}
+
+async fn check_metadata(
+    cluster: cluster::ShardedCluster,
+    ns: mongodb::Namespace,
+) -> mongodb::error::Result<Outcome> {
+    let report = cluster.check_metadata(&ns).await?;
+    log::info!(
+        "{} chunk(s) on {} shard(s): {:?}",
+        report.total_chunks(),
+        report.shard_chunks.len(),
+        report.shard_chunks
+    );
+    if let Some(version) = report.collection_version {
+        log::info!("collection version {}|{}", version.time, version.increment);
+    }
+    for problem in report.problems.iter() {
+        log::error!("{}", problem);
+    }
+    if !report.problems.is_empty() {
+        log::error!(
+            "routing table of {} has {} problem(s), orphan results for it cannot be trusted",
+            ns,
+            report.problems.len()
+        );
+        return Ok(Outcome::Problems);
+    }
+    log::info!("routing table of {} is consistent", ns);
+    Ok(Outcome::Clean)
+}
+
+async fn check_collection(
+    cluster: cluster::ShardedCluster,
+    ns: mongodb::Namespace,
+) -> mongodb::error::Result<Outcome> {
+    let report = cluster.check_collection(&ns).await?;
+    if !report.shards_without_collection.is_empty() {
+        log::info!(
+            "{} does not exist on shard(s) {:?}",
+            ns,
+            report.shards_without_collection
+        );
+    }
+    for mismatch in report.mismatches.iter() {
+        log::error!("{}", mismatch);
+    }
+    if !report.mismatches.is_empty() {
+        log::error!(
+            "{} has {} mismatch(es) across shards, documents of a stale copy of the collection look like orphans",
+            ns,
+            report.mismatches.len()
+        );
+        return Ok(Outcome::Problems);
+    }
+    log::info!("{} is consistent on every shard", ns);
+    Ok(Outcome::Clean)
 }
 
 async fn print(
     cluster: cluster::ShardedCluster,
     ns: mongodb::Namespace,
     verbose: bool,
-) -> mongodb::error::Result<()> {
+) -> mongodb::error::Result<Outcome> {
     let orphans = cluster.find_orphaned(&ns).await?;
     log::trace!("{:?}", orphans);
+    if orphans.is_incomplete() {
+        log::warn!("scan was interrupted, the results below are INCOMPLETE");
+    }
     log::info!(
         "found {} orphans on {} shard(s): {:?}",
         orphans.cluster_total(),
         orphans.num_shards(),
         orphans.shard_totals(),
     );
-    if verbose == true {
+    if verbose {
         log::info!("{:?}", orphans.shard_map());
     }
-    Ok(())
+    Ok(Outcome::Clean)
+}
+
+async fn diff(
+    cluster: cluster::ShardedCluster,
+    ns: mongodb::Namespace,
+    verbose: bool,
+) -> mongodb::error::Result<Outcome> {
+    let report = cluster.diff_orphaned(&ns).await?;
+    log::info!(
+        "{} divergent, {} identical, {} unique orphan(s)",
+        report.divergent.len(),
+        report.identical.len(),
+        report.unique.len(),
+    );
+    for (orphan, diff) in report.divergent.iter() {
+        log::info!(
+            "{:?} on shard {} differs from owning shard {}: {}",
+            orphan.id._id,
+            orphan.shard,
+            orphan.owner,
+            diff
+        );
+    }
+    if verbose {
+        log::info!(
+            "identical: {:?}",
+            report
+                .identical
+                .iter()
+                .map(|o| o.id._id)
+                .collect::<Vec<_>>()
+        );
+        log::info!(
+            "unique: {:?}",
+            report.unique.iter().map(|o| o.id._id).collect::<Vec<_>>()
+        );
+    }
+    Ok(Outcome::Clean)
+}
+
+async fn recover(
+    cluster: cluster::ShardedCluster,
+    ns: mongodb::Namespace,
+    archive_ns: Option<String>,
+    options: write::WriteOptions,
+    dry_run: bool,
+) -> mongodb::error::Result<Outcome> {
+    let archive_ns = archive_ns.map(|archive| util::parse_ns(archive.as_str()));
+    let summary = cluster
+        .recover_orphaned(&ns, archive_ns.as_ref(), &options, dry_run)
+        .await?;
+    if dry_run {
+        summary.plan.log();
+    }
+    log::info!(
+        "{}recovered {} orphan(s), archived {}, {} conflict(s), skipped {}, {} write(s) retried",
+        if dry_run { "[dry run] " } else { "" },
+        summary.recovered,
+        summary.archived,
+        summary.conflicts,
+        summary.skipped,
+        summary.retried,
+    );
+    if !summary.errors.is_empty() {
+        for err in summary.errors.iter() {
+            log::error!("{}", err);
+        }
+        log::error!(
+            "recovery stopped early, the counts above are of the orphans moved before it did"
+        );
+        return Ok(Outcome::Problems);
+    }
+    Ok(Outcome::Clean)
 }
 
 async fn update(
     cluster: cluster::ShardedCluster,
     ns: mongodb::Namespace,
     target_ns: Option<String>,
-) -> mongodb::error::Result<()> {
+    marker: marker::Marker,
+    options: write::WriteOptions,
+    dry_run: bool,
+) -> mongodb::error::Result<Outcome> {
     log::debug!("target ns of {:?}", target_ns);
-    if let Some(target) = target_ns {
-        let target_ns = util::parse_ns(target.as_str());
-        cluster.update_orphaned(&ns, Some(&target_ns)).await?;
-    } else {
-        cluster.update_orphaned(&ns, None).await?;
+    if dry_run {
+        log::info!(
+            "[dry run] orphans would be updated with {}",
+            marker.update("<owning shard>")
+        );
+        cluster.plan_update(&ns).await?.log();
+        return Ok(Outcome::Clean);
+    }
+    let target_ns = target_ns.map(|target| util::parse_ns(target.as_str()));
+    let summary = cluster
+        .update_orphaned(&ns, target_ns.as_ref(), &marker, &options)
+        .await?;
+    summary.log();
+    if summary.has_errors() {
+        log::error!("one or more write batches failed, see errors above");
+        return Ok(Outcome::Problems);
+    }
+    Ok(Outcome::Clean)
+}
+
+async fn unmark(
+    cluster: cluster::ShardedCluster,
+    ns: mongodb::Namespace,
+    field: String,
+    run_id: Option<String>,
+    options: write::WriteOptions,
+    dry_run: bool,
+) -> mongodb::error::Result<Outcome> {
+    let mut plan = plan::DryRunPlan::default();
+    let summary = cluster
+        .unmark_orphaned(
+            &ns,
+            field.as_str(),
+            run_id.as_deref(),
+            &options,
+            dry_run.then_some(&mut plan),
+        )
+        .await?;
+    if dry_run {
+        plan.log();
+        log::info!(
+            "[dry run] {} document(s) would be unmarked",
+            summary.matched()
+        );
+        return Ok(Outcome::Clean);
+    }
+    summary.log();
+    if summary.has_errors() {
+        log::error!("one or more write batches failed, see errors above");
+        return Ok(Outcome::Problems);
+    }
+    Ok(Outcome::Clean)
+}
+
+/// record the outcome of the run and write every metric to the textfile, logging rather than failing the run if it
+/// cannot be written
+fn write_metrics(
+    metrics: &metrics::Metrics,
+    path: &std::path::Path,
+    ns: &mongodb::Namespace,
+    started: std::time::Instant,
+    result: &mongodb::error::Result<Outcome>,
+    shutdown: &shutdown::Shutdown,
+) {
+    if result.is_err() {
+        metrics.inc("orphanage_errors_total", metrics::ns_labels(ns), 1.0);
+    }
+    let incomplete = result.is_err() || shutdown.is_triggered();
+    metrics.set(
+        "orphanage_run_incomplete",
+        metrics::ns_labels(ns),
+        if incomplete { 1.0 } else { 0.0 },
+    );
+    metrics.set(
+        "orphanage_run_duration_seconds",
+        metrics::ns_labels(ns),
+        started.elapsed().as_secs_f64(),
+    );
+    metrics.set(
+        "orphanage_last_run_timestamp_seconds",
+        metrics::ns_labels(ns),
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64(),
+    );
+    if let Err(err) = metrics.write_textfile(path) {
+        log::error!("cannot write metrics to {}: {}", path.display(), err);
     }
-    Ok(())
 }
 
 #[tokio::main]
 async fn main() -> mongodb::error::Result<()> {
-    init_logging();
-
     let args = cli::args();
-
-    let cluster = cluster::ShardedCluster::new(&args.uri).await?;
+    telemetry::init(
+        args.log_format,
+        telemetry::TraceOptions {
+            file: args.trace_file.as_deref(),
+            otlp_endpoint: args.otlp_endpoint.as_deref(),
+        },
+    )?;
 
     let ns = mongodb::Namespace {
-        db: args.db,
-        coll: args.coll,
+        db: args.db.clone(),
+        coll: args.coll.clone(),
     };
+    let run_id = log_run_id(&args.mode);
+    let span = tracing::info_span!("run", run_id = %run_id, ns = %ns);
+    span.in_scope(|| log::info!("starting run {}", run_id));
+    match run(args, ns).instrument(span).await {
+        Ok(0) => {
+            telemetry::shutdown();
+            Ok(())
+        }
+        Ok(code) => telemetry::exit(code),
+        Err(err) => {
+            telemetry::shutdown();
+            Err(err)
+        }
+    }
+}
 
+/// run the mode, writing metrics however it ends, and return the exit code
+async fn run(args: cli::Args, ns: mongodb::Namespace) -> mongodb::error::Result<i32> {
+    let started = std::time::Instant::now();
+    let shutdown = shutdown::Shutdown::install();
+    let metrics = std::sync::Arc::new(metrics::Metrics::default());
+    let metrics_file = args.metrics_file.clone();
+    let result = run_mode(args, ns.clone(), shutdown.clone(), metrics.clone()).await;
+    if let Some(path) = metrics_file.as_deref() {
+        write_metrics(&metrics, path, &ns, started, &result, &shutdown);
+    }
+    let outcome = result?;
+
+    if shutdown.is_triggered() {
+        log::warn!("run was interrupted, the results above are incomplete");
+        return Ok(130);
+    }
+    Ok(match outcome {
+        Outcome::Clean => 0,
+        Outcome::Problems => 1,
+    })
+}
+
+async fn run_mode(
+    args: cli::Args,
+    ns: mongodb::Namespace,
+    shutdown: shutdown::Shutdown,
+    metrics: std::sync::Arc<metrics::Metrics>,
+) -> mongodb::error::Result<Outcome> {
+    let mut host_map = args.config_host_map;
+    for (internal, reachable) in args.host_map {
+        host_map.insert(internal, reachable);
+    }
+    let options = cluster::ClusterOptions {
+        snapshot: args.snapshot,
+        shutdown: shutdown.clone(),
+        progress_interval: (args.progress_interval > 0)
+            .then(|| std::time::Duration::from_secs(args.progress_interval)),
+        metrics: metrics.clone(),
+        health: (args.health_interval > 0).then(|| health::HealthOptions {
+            interval: std::time::Duration::from_secs(args.health_interval),
+            thresholds: health::Thresholds {
+                max_queued_ops: args.max_queued_ops,
+                max_cache_used: args.max_cache_used / 100.0,
+                max_cache_dirty: args.max_cache_dirty / 100.0,
+                max_replication_lag: std::time::Duration::from_secs(args.max_replication_lag),
+            },
+            max_pause: std::time::Duration::from_secs(args.max_pause),
+        }),
+        shard_read_preference: args.shard_read_preference.map(|mode| {
+            std::sync::Arc::new(read_preference::ShardReadPreference::new(
+                mode,
+                args.shard_read_tags,
+            ))
+        }),
+        host_map,
+        shard_config: args.shard_config,
+        allow_collscan: args.allow_collscan,
+        concurrency: args.concurrency as usize,
+    };
+    let mut cluster = cluster::ShardedCluster::new(&args.uri, options).await?;
+
+    if !args.skip_preflight {
+        let access = match &args.mode {
+            _ if args.dry_run => preflight::Access::Read,
+            cli::Mode::Update { .. } | cli::Mode::Unmark { .. } => preflight::Access::Update,
+            cli::Mode::Recover { archive_ns } => preflight::Access::Recover {
+                archive_ns: archive_ns.as_deref().map(util::parse_ns),
+            },
+            _ => preflight::Access::Read,
+        };
+        // only the modes scanning chunk ranges sample shard health
+        let scans = matches!(
+            args.mode,
+            cli::Mode::Print { .. }
+                | cli::Mode::Diff { .. }
+                | cli::Mode::Recover { .. }
+                | cli::Mode::Update { .. }
+        );
+        let health = scans && args.health_interval > 0;
+        let report = preflight::check(&cluster, &ns, &access, health).await?;
+        if !report.ok {
+            return Err(std::io::Error::other("preflight checks failed, see errors above").into());
+        }
+        if health && !report.health {
+            cluster.disable_health();
+        }
+    }
+
+    let options = write::WriteOptions {
+        batch_size: args.batch_size as usize,
+        write_concern: args
+            .write_concern
+            .as_deref()
+            .map(write::parse_write_concern),
+        retries: args.write_retries,
+    };
     match args.mode {
         cli::Mode::Estimate => estimate(cluster, ns).await,
+        cli::Mode::CheckMetadata => check_metadata(cluster, ns).await,
+        cli::Mode::CheckCollection => check_collection(cluster, ns).await,
         cli::Mode::Print { verbose } => print(cluster, ns, verbose).await,
-        cli::Mode::Update { target_ns } => update(cluster, ns, target_ns).await,
+        cli::Mode::Diff { verbose } => diff(cluster, ns, verbose).await,
+        cli::Mode::Recover { archive_ns } => {
+            recover(cluster, ns, archive_ns, options, args.dry_run).await
+        }
+        cli::Mode::Update {
+            target_ns,
+            field,
+            value,
+            run_id,
+            stamp_time,
+            stamp_owner,
+        } => {
+            let marker = marker::Marker {
+                field,
+                value: marker::parse_value(value.as_str()),
+                run_id,
+                marked_at: stamp_time.then(mongodb::bson::DateTime::now),
+                stamp_owner,
+            };
+            update(cluster, ns, target_ns, marker, options, args.dry_run).await
+        }
+        cli::Mode::Unmark { field, run_id } => {
+            unmark(cluster, ns, field, run_id, options, args.dry_run).await
+        }
     }
 }
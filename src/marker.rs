@@ -0,0 +1,127 @@
+use mongodb::bson;
+
+/// How update_orphaned marks each orphan
+///
+/// The marker field is always set to the marker value. When requested, run metadata (a run id, the time the run
+/// started and the shard that owns the orphan's chunk) is stamped under `<field>_meta`
+#[derive(Debug, Clone)]
+pub struct Marker {
+    pub field: String,
+    pub value: bson::Bson,
+    pub run_id: Option<String>,
+    pub marked_at: Option<bson::DateTime>,
+    pub stamp_owner: bool,
+}
+
+impl Marker {
+    /// name of the sub-document holding run metadata
+    pub fn meta_field(&self) -> String {
+        meta_field(&self.field)
+    }
+
+    /// the update document that marks an orphan whose chunk is owned by the given shard
+    pub fn update(&self, owner: &str) -> bson::Document {
+        let meta = self.meta_field();
+        let mut set = bson::doc! { self.field.as_str(): self.value.clone() };
+        if let Some(run_id) = &self.run_id {
+            set.insert(format!("{}.run_id", meta), run_id);
+        }
+        if let Some(marked_at) = &self.marked_at {
+            set.insert(format!("{}.marked_at", meta), marked_at);
+        }
+        if self.stamp_owner {
+            set.insert(format!("{}.owner", meta), owner);
+        }
+        bson::doc! { "$set": set }
+    }
+}
+
+fn meta_field(field: &str) -> String {
+    format!("{}_meta", field)
+}
+
+/// filter matching documents marked with the given field, optionally only those stamped with a run id
+pub fn unmark_filter(field: &str, run_id: Option<&str>) -> bson::Document {
+    let mut filter = bson::doc! { field: { "$exists": true } };
+    if let Some(run_id) = run_id {
+        filter.insert(format!("{}.run_id", meta_field(field)), run_id);
+    }
+    filter
+}
+
+/// the update document that removes a marker field and its run metadata
+pub fn unmark_update(field: &str) -> bson::Document {
+    bson::doc! { "$unset": { field: "", meta_field(field): "" } }
+}
+
+/// parse a marker value given on the command line -- booleans and numbers keep their type, anything else is a string
+pub fn parse_value(value: &str) -> bson::Bson {
+    if let Ok(b) = value.parse::<bool>() {
+        return bson::Bson::Boolean(b);
+    }
+    if let Ok(i) = value.parse::<i64>() {
+        return bson::Bson::Int64(i);
+    }
+    if let Ok(f) = value.parse::<f64>() {
+        return bson::Bson::Double(f);
+    }
+    bson::Bson::String(value.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::{doc, Bson, DateTime};
+
+    use super::Marker;
+
+    fn marker() -> Marker {
+        Marker {
+            field: String::from("orphaned"),
+            value: Bson::Boolean(true),
+            run_id: None,
+            marked_at: None,
+            stamp_owner: false,
+        }
+    }
+
+    #[test]
+    fn default_marker_update() {
+        let expected = doc! { "$set": { "orphaned": true } };
+        assert_eq!(expected, marker().update("shard01"));
+    }
+
+    #[test]
+    fn marker_update_with_metadata() {
+        let marked_at = DateTime::from_millis(0);
+        let marker = Marker {
+            field: String::from("cleanup.flag"),
+            value: Bson::String(String::from("orphan")),
+            run_id: Some(String::from("run-1")),
+            marked_at: Some(marked_at),
+            stamp_owner: true,
+        };
+        let expected = doc! { "$set": {
+            "cleanup.flag": "orphan",
+            "cleanup.flag_meta.run_id": "run-1",
+            "cleanup.flag_meta.marked_at": marked_at,
+            "cleanup.flag_meta.owner": "shard01",
+        } };
+        assert_eq!(expected, marker.update("shard01"));
+    }
+
+    #[test]
+    fn unmark_scoped_to_run_id() {
+        let expected = doc! { "orphaned": { "$exists": true }, "orphaned_meta.run_id": "run-1" };
+        assert_eq!(expected, super::unmark_filter("orphaned", Some("run-1")));
+        let expected = doc! { "$unset": { "orphaned": "", "orphaned_meta": "" } };
+        assert_eq!(expected, super::unmark_update("orphaned"));
+    }
+
+    #[test]
+    fn parse_marker_values() {
+        assert_eq!(Bson::Boolean(false), super::parse_value("false"));
+        assert_eq!(Bson::Int64(3), super::parse_value("3"));
+        assert_eq!(Bson::Double(1.5), super::parse_value("1.5"));
+        assert_eq!(Bson::String(String::from("yes")), super::parse_value("yes"));
+    }
+}
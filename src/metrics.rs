@@ -0,0 +1,258 @@
+use std::{
+    collections::BTreeMap, fmt::Write as _, io::Write as _, path::Path, sync::Mutex, time::Duration,
+};
+
+/// name, help text and type of every metric exposed
+const METRICS: &[(&str, &str, &str)] = &[
+    (
+        "orphanage_ranges_scanned_total",
+        "Chunk ranges scanned on shards that do not own them",
+        "counter",
+    ),
+    (
+        "orphanage_orphans_found_total",
+        "Orphaned documents found",
+        "counter",
+    ),
+    (
+        "orphanage_query_duration_seconds",
+        "Time spent scanning a chunk range on a shard",
+        "summary",
+    ),
+    (
+        "orphanage_write_batches_total",
+        "Write batches issued against shards",
+        "counter",
+    ),
+    (
+        "orphanage_documents_modified_total",
+        "Documents modified by write batches",
+        "counter",
+    ),
+    (
+        "orphanage_errors_total",
+        "Errors while scanning or writing",
+        "counter",
+    ),
+    (
+        "orphanage_run_duration_seconds",
+        "Duration of the last run",
+        "gauge",
+    ),
+    (
+        "orphanage_run_incomplete",
+        "1 if the last run was interrupted or failed before completing",
+        "gauge",
+    ),
+    (
+        "orphanage_last_run_timestamp_seconds",
+        "Unix time the last run finished",
+        "gauge",
+    ),
+];
+
+pub type Labels = Vec<(&'static str, String)>;
+type Series = (&'static str, Labels);
+
+/// Counters, gauges and summaries for a run, rendered in the Prometheus text exposition format
+///
+/// written as a textfile for node_exporter's textfile collector, since orphanage runs as a job rather than a service
+#[derive(Debug, Default)]
+pub struct Metrics {
+    samples: Mutex<BTreeMap<Series, f64>>,
+    /// sum and count of each summary series
+    summaries: Mutex<BTreeMap<Series, (f64, u64)>>,
+}
+
+impl Metrics {
+    pub fn inc(&self, name: &'static str, labels: Labels, value: f64) {
+        *self
+            .samples
+            .lock()
+            .unwrap()
+            .entry((name, labels))
+            .or_default() += value;
+    }
+
+    pub fn set(&self, name: &'static str, labels: Labels, value: f64) {
+        self.samples.lock().unwrap().insert((name, labels), value);
+    }
+
+    /// record a duration against a summary, exposed as its _sum and _count
+    pub fn observe(&self, name: &'static str, labels: Labels, seconds: f64) {
+        let mut summaries = self.summaries.lock().unwrap();
+        let (sum, count) = summaries.entry((name, labels)).or_default();
+        *sum += seconds;
+        *count += 1;
+    }
+
+    /// record a completed scan of a chunk range on a shard that does not own it
+    pub fn range_scanned(
+        &self,
+        ns: &mongodb::Namespace,
+        shard: &str,
+        elapsed: Duration,
+        orphans: u64,
+    ) {
+        self.inc(
+            "orphanage_ranges_scanned_total",
+            shard_labels(ns, shard),
+            1.0,
+        );
+        self.inc(
+            "orphanage_orphans_found_total",
+            shard_labels(ns, shard),
+            orphans as f64,
+        );
+        self.observe(
+            "orphanage_query_duration_seconds",
+            shard_labels(ns, shard),
+            elapsed.as_secs_f64(),
+        );
+    }
+
+    /// record writes issued against a shard, a single write counting as a batch of one
+    pub fn writes(
+        &self,
+        ns: &mongodb::Namespace,
+        shard: &str,
+        batches: usize,
+        modified: u64,
+        errors: usize,
+    ) {
+        self.inc(
+            "orphanage_write_batches_total",
+            shard_labels(ns, shard),
+            batches as f64,
+        );
+        self.inc(
+            "orphanage_documents_modified_total",
+            shard_labels(ns, shard),
+            modified as f64,
+        );
+        self.inc(
+            "orphanage_errors_total",
+            shard_labels(ns, shard),
+            errors as f64,
+        );
+    }
+
+    /// render every sample in the text exposition format
+    pub fn render(&self) -> String {
+        let samples = self.samples.lock().unwrap();
+        let summaries = self.summaries.lock().unwrap();
+        let mut out = String::new();
+        for (name, help, kind) in METRICS {
+            let series = samples
+                .iter()
+                .filter(|((n, _), _)| n == name)
+                .collect::<Vec<_>>();
+            let summary_series = summaries
+                .iter()
+                .filter(|((n, _), _)| n == name)
+                .collect::<Vec<_>>();
+            if series.is_empty() && summary_series.is_empty() {
+                continue;
+            }
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            for ((_, labels), value) in series {
+                let _ = writeln!(out, "{}{} {}", name, format_labels(labels), value);
+            }
+            for ((_, labels), (sum, count)) in summary_series {
+                let _ = writeln!(out, "{}_sum{} {}", name, format_labels(labels), sum);
+                let _ = writeln!(out, "{}_count{} {}", name, format_labels(labels), count);
+            }
+        }
+        out
+    }
+
+    /// write the rendered metrics to a file, through a temporary file so collectors never read a partial write
+    pub fn write_textfile(&self, path: &Path) -> std::io::Result<()> {
+        let tmp = path.with_extension("prom.tmp");
+        let mut file = std::fs::File::create(&tmp)?;
+        file.write_all(self.render().as_bytes())?;
+        file.sync_all()?;
+        std::fs::rename(&tmp, path)
+    }
+}
+
+/// labels for a metric scoped to a namespace and shard
+pub fn shard_labels(ns: &mongodb::Namespace, shard: &str) -> Labels {
+    vec![("ns", ns.to_string()), ("shard", shard.to_string())]
+}
+
+/// labels for a metric scoped to a namespace
+pub fn ns_labels(ns: &mongodb::Namespace) -> Labels {
+    vec![("ns", ns.to_string())]
+}
+
+fn format_labels(labels: &Labels) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let pairs = labels
+        .iter()
+        .map(|(key, value)| format!("{}=\"{}\"", key, escape(value)))
+        .collect::<Vec<String>>();
+    format!("{{{}}}", pairs.join(","))
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Metrics;
+
+    fn ns() -> mongodb::Namespace {
+        mongodb::Namespace {
+            db: String::from("test"),
+            coll: String::from("test"),
+        }
+    }
+
+    #[test]
+    fn render_counters_and_summaries() {
+        let metrics = Metrics::default();
+        metrics.inc(
+            "orphanage_orphans_found_total",
+            super::shard_labels(&ns(), "shard01"),
+            2.0,
+        );
+        metrics.inc(
+            "orphanage_orphans_found_total",
+            super::shard_labels(&ns(), "shard01"),
+            1.0,
+        );
+        metrics.observe(
+            "orphanage_query_duration_seconds",
+            super::shard_labels(&ns(), "shard01"),
+            0.5,
+        );
+        metrics.observe(
+            "orphanage_query_duration_seconds",
+            super::shard_labels(&ns(), "shard01"),
+            1.5,
+        );
+        let expected = "\
+# HELP orphanage_orphans_found_total Orphaned documents found
+# TYPE orphanage_orphans_found_total counter
+orphanage_orphans_found_total{ns=\"test.test\",shard=\"shard01\"} 3
+# HELP orphanage_query_duration_seconds Time spent scanning a chunk range on a shard
+# TYPE orphanage_query_duration_seconds summary
+orphanage_query_duration_seconds_sum{ns=\"test.test\",shard=\"shard01\"} 2
+orphanage_query_duration_seconds_count{ns=\"test.test\",shard=\"shard01\"} 2
+";
+        assert_eq!(expected, metrics.render());
+    }
+
+    #[test]
+    fn escape_label_values() {
+        assert_eq!("a\\\"b\\n", super::escape("a\"b\n"));
+    }
+}
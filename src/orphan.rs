@@ -1,11 +1,12 @@
 use std::collections::HashMap;
 
-use crate::db::Id;
+use crate::{db::Id, plan::DryRunPlan};
 
-/// A single orphan ID as well as the shard it was found on
-#[derive(Debug)]
+/// A single orphan ID, the shard it was found on, and the shard that owns its chunk
+#[derive(Debug, Clone)]
 pub struct Orphan {
     pub shard: String,
+    pub owner: String,
     pub id: Id,
 }
 
@@ -15,7 +16,8 @@ pub struct Orphan {
 #[derive(Debug)]
 pub struct OrphanSummary {
     total_count: usize,
-    shard_map: HashMap<String, Vec<Id>>,
+    shard_map: HashMap<String, Vec<Orphan>>,
+    incomplete: bool,
 }
 
 impl OrphanSummary {
@@ -23,19 +25,29 @@ impl OrphanSummary {
         let total_count = 0;
         let mut shard_map = HashMap::new();
         for shard in shards.iter() {
-            shard_map.insert(shard.clone().to_owned(), Vec::<Id>::new());
+            shard_map.insert(shard.to_string(), Vec::<Orphan>::new());
         }
         OrphanSummary {
             total_count,
             shard_map,
+            incomplete: false,
         }
     }
 
+    /// flag the summary as partial, e.g. because the scan was interrupted
+    pub fn mark_incomplete(&mut self) {
+        self.incomplete = true;
+    }
+
+    pub fn is_incomplete(&self) -> bool {
+        self.incomplete
+    }
+
     pub fn add(&mut self, orphan: Orphan) {
         self.shard_map
             .get_mut(&orphan.shard)
             .expect("cannot find shard in orphan shard_map")
-            .push(orphan.id);
+            .push(orphan);
         self.total_count += 1;
     }
 
@@ -47,8 +59,8 @@ impl OrphanSummary {
         let shard_totals: HashMap<String, usize> = HashMap::from_iter(
             self.shard_map
                 .iter()
-                .filter(|(_, ids)| ids.len() > 0)
-                .map(|(key, ids)| (key.clone().to_owned(), ids.len())),
+                .filter(|(_, orphans)| !orphans.is_empty())
+                .map(|(key, orphans)| (key.clone().to_owned(), orphans.len())),
         );
         shard_totals
     }
@@ -57,13 +69,87 @@ impl OrphanSummary {
         let filtered = HashMap::from_iter(
             self.shard_map
                 .iter()
-                .filter(|(_, ids)| ids.len() > 0)
-                .map(|(name, ids)| (name.to_owned(), ids.to_owned())),
+                .filter(|(_, orphans)| !orphans.is_empty())
+                .map(|(name, orphans)| {
+                    (
+                        name.to_owned(),
+                        orphans.iter().map(|orphan| orphan.id.clone()).collect(),
+                    )
+                }),
         );
         filtered
     }
 
+    /// iterate over every orphan found, across all shards
+    pub fn orphans(&self) -> impl Iterator<Item = &Orphan> {
+        self.shard_map.values().flatten()
+    }
+
     pub fn num_shards(&self) -> usize {
-        self.shard_map.values().filter(|ids| ids.len() > 0).count()
+        self.shard_map
+            .values()
+            .filter(|orphans| !orphans.is_empty())
+            .count()
+    }
+}
+
+/// A shard that owns no chunk of a namespace but still holds documents of it, all of which are orphans
+#[derive(Debug, PartialEq)]
+pub struct Remnant {
+    pub shard: String,
+    /// estimated from the collection's metadata on the shard
+    pub documents: u64,
+}
+
+/// shards holding documents of a namespace without owning any of its chunks, given the documents and chunks on each
+pub fn remnants(documents: &HashMap<String, u64>, chunks: &HashMap<String, u64>) -> Vec<Remnant> {
+    let mut remnants = documents
+        .iter()
+        .filter(|(shard, documents)| {
+            **documents > 0 && chunks.get(*shard).copied().unwrap_or(0) == 0
+        })
+        .map(|(shard, documents)| Remnant {
+            shard: shard.clone(),
+            documents: *documents,
+        })
+        .collect::<Vec<_>>();
+    remnants.sort_by(|a, b| a.shard.cmp(&b.shard));
+    remnants
+}
+
+/// Results of moving unique orphans back through the router, counted per orphan
+#[derive(Debug, Default)]
+pub struct RecoverySummary {
+    pub recovered: usize,
+    pub archived: usize,
+    pub conflicts: usize,
+    pub skipped: usize,
+    pub retried: usize,
+    pub plan: DryRunPlan,
+    /// the write that stopped the run, if any, with what it left behind
+    pub errors: Vec<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::Remnant;
+
+    #[test]
+    fn shards_without_chunks() {
+        let documents = HashMap::from([
+            (String::from("shard01"), 100),
+            (String::from("shard02"), 0),
+            (String::from("shard03"), 7),
+        ]);
+        let chunks = HashMap::from([(String::from("shard01"), 3)]);
+        assert_eq!(
+            vec![Remnant {
+                shard: String::from("shard03"),
+                documents: 7
+            }],
+            super::remnants(&documents, &chunks)
+        );
     }
 }
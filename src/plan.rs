@@ -0,0 +1,71 @@
+use std::collections::BTreeMap;
+
+use mongodb::bson;
+
+/// number of ids kept per action and shard to show as a sample in the plan
+const SAMPLE_SIZE: usize = 10;
+
+/// A write that would be issued against a shard
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Action {
+    Archive,
+    Insert,
+    Update,
+    Delete,
+}
+
+impl Action {
+    fn describe(&self) -> &'static str {
+        match self {
+            Action::Archive => "archive",
+            Action::Insert => "insert (through router)",
+            Action::Update => "update",
+            Action::Delete => "delete",
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct PlanEntry {
+    count: usize,
+    sample_ids: Vec<bson::oid::ObjectId>,
+}
+
+/// Writes that would have been issued if not in dry run mode, grouped by action and the shard they target
+///
+/// writes that go through the router are grouped under the shard that owns the document
+#[derive(Debug, Default)]
+pub struct DryRunPlan {
+    entries: BTreeMap<(Action, String), PlanEntry>,
+}
+
+impl DryRunPlan {
+    pub fn add(&mut self, action: Action, shard: &str, id: &bson::oid::ObjectId) {
+        let entry = self.entries.entry((action, shard.to_string())).or_default();
+        entry.count += 1;
+        if entry.sample_ids.len() < SAMPLE_SIZE {
+            entry.sample_ids.push(*id);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// log one line per action and shard with the number of documents and a sample of their ids
+    pub fn log(&self) {
+        if self.is_empty() {
+            log::info!("[dry run] no writes would be issued");
+            return;
+        }
+        for ((action, shard), entry) in self.entries.iter() {
+            log::info!(
+                "[dry run] would {} {} document(s) on shard {}, e.g. {:?}",
+                action.describe(),
+                entry.count,
+                shard,
+                entry.sample_ids
+            );
+        }
+    }
+}
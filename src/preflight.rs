@@ -0,0 +1,450 @@
+use std::fmt;
+
+use mongodb::bson;
+use serde::Deserialize;
+
+use crate::{cluster::ShardedCluster, db};
+
+/// first major version where reads and writes sent directly to a shard require the directShardOperations action
+const DIRECT_SHARD_OPERATIONS_VERSION: i32 = 8;
+
+/// What a run does against the namespace, and so which privileges it needs
+#[derive(Debug, Clone)]
+pub enum Access {
+    /// scans only
+    Read,
+    /// marks or unmarks orphans on the shards
+    Update,
+    /// inserts orphans through the router (archiving them first, if set) and deletes them from the shards
+    Recover {
+        archive_ns: Option<mongodb::Namespace>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Resource {
+    Collection { db: String, collection: String },
+    Cluster,
+}
+
+impl fmt::Display for Resource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Resource::Collection { db, collection } => write!(f, "{}.{}", db, collection),
+            Resource::Cluster => write!(f, "the cluster"),
+        }
+    }
+}
+
+/// A single action a client must be allowed to perform on a resource
+#[derive(Debug, Clone, PartialEq)]
+pub struct Requirement {
+    resource: Resource,
+    action: &'static str,
+}
+
+impl Requirement {
+    fn collection(db: &str, collection: &str, action: &'static str) -> Self {
+        Requirement {
+            resource: Resource::Collection {
+                db: db.to_string(),
+                collection: collection.to_string(),
+            },
+            action,
+        }
+    }
+
+    fn on_ns(ns: &mongodb::Namespace, action: &'static str) -> Self {
+        Self::collection(&ns.db, &ns.coll, action)
+    }
+}
+
+impl fmt::Display for Requirement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} on {}", self.action, self.resource)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ConnectionStatus {
+    #[serde(rename = "authInfo")]
+    auth_info: AuthInfo,
+}
+
+#[derive(Debug, Deserialize)]
+struct AuthInfo {
+    #[serde(rename = "authenticatedUsers", default)]
+    authenticated_users: Vec<bson::Document>,
+    #[serde(rename = "authenticatedUserPrivileges", default)]
+    privileges: Vec<Privilege>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Privilege {
+    resource: PrivilegeResource,
+    actions: Vec<String>,
+}
+
+/// a resource document as reported by connectionStatus, see
+/// https://www.mongodb.com/docs/manual/reference/resource-document/
+#[derive(Debug, Default, Deserialize)]
+struct PrivilegeResource {
+    db: Option<String>,
+    collection: Option<String>,
+    #[serde(default)]
+    cluster: bool,
+    #[serde(rename = "anyResource", default)]
+    any_resource: bool,
+}
+
+impl PrivilegeResource {
+    /// an empty db or collection name matches any database or collection
+    fn covers(&self, resource: &Resource) -> bool {
+        if self.any_resource {
+            return true;
+        }
+        match resource {
+            Resource::Cluster => self.cluster,
+            Resource::Collection { db, collection } => {
+                let matches = |pattern: &Option<String>, name: &str| {
+                    pattern
+                        .as_deref()
+                        .is_some_and(|pattern| pattern.is_empty() || pattern == name)
+                };
+                matches(&self.db, db) && matches(&self.collection, collection)
+            }
+        }
+    }
+}
+
+/// requirements not granted by any of the privileges
+fn missing(privileges: &[Privilege], required: &[Requirement]) -> Vec<Requirement> {
+    required
+        .iter()
+        .filter(|requirement| {
+            !privileges.iter().any(|privilege| {
+                privilege.resource.covers(&requirement.resource)
+                    && privilege
+                        .actions
+                        .iter()
+                        .any(|action| action == requirement.action)
+            })
+        })
+        .cloned()
+        .collect()
+}
+
+/// privileges needed on the router to read the routing table and, when recovering, to write through it
+fn router_requirements(access: &Access, ns: &mongodb::Namespace) -> Vec<Requirement> {
+    let mut required = vec![
+        Requirement::collection("config", "shards", "find"),
+        Requirement::collection("config", "collections", "find"),
+        Requirement::collection("config", "chunks", "find"),
+        Requirement::collection("config", "databases", "find"),
+    ];
+    if let Access::Recover { archive_ns } = access {
+        required.push(Requirement::on_ns(ns, "insert"));
+        if let Some(archive_ns) = archive_ns {
+            required.push(Requirement::on_ns(archive_ns, "insert"));
+        }
+    }
+    required
+}
+
+/// actions of the clusterMonitor role health sampling runs on every shard
+const HEALTH_ACTIONS: &[&str] = &["serverStatus", "replSetGetStatus", "replSetGetConfig"];
+
+/// privileges needed on every shard to scan the namespace and write to it directly
+fn shard_requirements(
+    access: &Access,
+    ns: &mongodb::Namespace,
+    direct_operations: bool,
+) -> Vec<Requirement> {
+    let mut required = vec![
+        Requirement::on_ns(ns, "find"),
+        Requirement::on_ns(ns, "listIndexes"),
+    ];
+    match access {
+        Access::Read => {}
+        Access::Update => required.push(Requirement::on_ns(ns, "update")),
+        Access::Recover { .. } => required.push(Requirement::on_ns(ns, "remove")),
+    }
+    if direct_operations {
+        required.push(Requirement {
+            resource: Resource::Cluster,
+            action: "directShardOperations",
+        });
+    }
+    required
+}
+
+/// privileges needed on every shard to sample its health, without which scans run unthrottled
+fn health_requirements() -> Vec<Requirement> {
+    HEALTH_ACTIONS
+        .iter()
+        .map(|action| Requirement {
+            resource: Resource::Cluster,
+            action,
+        })
+        .collect()
+}
+
+/// Missing privileges of a single client, or none if it is not authenticated (authentication is then presumably
+/// disabled, and anything else fails on the first command anyway)
+#[derive(Debug)]
+struct ClientReport {
+    name: String,
+    authenticated: bool,
+    missing: Vec<Requirement>,
+    /// missing privileges only health sampling needs
+    missing_health: Vec<Requirement>,
+}
+
+async fn check_client(
+    name: &str,
+    client: &mongodb::Client,
+    required: &[Requirement],
+    health_required: &[Requirement],
+) -> mongodb::error::Result<ClientReport> {
+    let status = client
+        .database("admin")
+        .run_command(
+            bson::doc! { "connectionStatus": 1, "showPrivileges": true },
+            None,
+        )
+        .await?;
+    let status: ConnectionStatus = bson::from_document(status)?;
+    let authenticated = !status.auth_info.authenticated_users.is_empty();
+    let missing = |required| {
+        if authenticated {
+            missing(&status.auth_info.privileges, required)
+        } else {
+            Vec::new()
+        }
+    };
+    Ok(ClientReport {
+        name: name.to_string(),
+        authenticated,
+        missing: missing(required),
+        missing_health: missing(health_required),
+    })
+}
+
+/// Result of the preflight checks of a run
+#[derive(Debug)]
+pub struct Report {
+    /// every client has every privilege the run needs
+    pub ok: bool,
+    /// every shard client may sample its shard's health, always false if health sampling was not checked
+    pub health: bool,
+}
+
+/// check the privileges of the router and every shard client for a run, and those health sampling needs on every shard
+/// if set, logging a report
+///
+/// missing health sampling privileges are only warned about, as the run can go on without throttling
+pub async fn check(
+    cluster: &ShardedCluster,
+    ns: &mongodb::Namespace,
+    access: &Access,
+    health: bool,
+) -> mongodb::error::Result<Report> {
+    let version = db::get_version(&cluster.router).await?;
+    let direct_operations = version
+        .split('.')
+        .next()
+        .and_then(|major| major.parse::<i32>().ok())
+        .is_some_and(|major| major >= DIRECT_SHARD_OPERATIONS_VERSION);
+
+    let mut reports = vec![
+        check_client(
+            "router",
+            &cluster.router,
+            &router_requirements(access, ns),
+            &[],
+        )
+        .await?,
+    ];
+    let shard_required = shard_requirements(access, ns, direct_operations);
+    let health_required = if health {
+        health_requirements()
+    } else {
+        Vec::new()
+    };
+    let mut shard_names = cluster.shards.keys().collect::<Vec<_>>();
+    shard_names.sort();
+    for shard_name in shard_names {
+        let client = &cluster.shards[shard_name];
+        reports.push(
+            check_client(
+                &format!("shard {}", shard_name),
+                client,
+                &shard_required,
+                &health_required,
+            )
+            .await?,
+        );
+    }
+
+    let mut ok = true;
+    let mut health_ok = health;
+    for report in reports {
+        for requirement in report.missing_health.iter() {
+            health_ok = false;
+            log::warn!("preflight: {} is missing {}", report.name, requirement);
+        }
+        if !report.authenticated {
+            log::warn!(
+                "preflight: {} connection is not authenticated, skipping its permission checks",
+                report.name
+            );
+        } else if report.missing.is_empty() {
+            log::info!("preflight: {} has every required privilege", report.name);
+        } else {
+            ok = false;
+            for requirement in report.missing {
+                log::error!("preflight: {} is missing {}", report.name, requirement);
+            }
+        }
+    }
+    if !ok {
+        log::error!(
+            "preflight: grant the missing actions (e.g. the read/readWrite roles on the namespace, read on the config \
+             database{}) to the users orphanage connects as, or rerun with --skip-preflight",
+            if direct_operations {
+                ", and the directShardOperations role on each shard"
+            } else {
+                ""
+            }
+        );
+    }
+    if health && !health_ok {
+        log::warn!(
+            "preflight: shard health cannot be sampled, throttling will be disabled -- grant the clusterMonitor role on \
+             each shard to throttle, or set --health-interval 0 to silence this"
+        );
+    }
+    Ok(Report {
+        ok,
+        health: health_ok,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Access, Privilege, PrivilegeResource, Requirement};
+
+    fn ns() -> mongodb::Namespace {
+        mongodb::Namespace {
+            db: String::from("test"),
+            coll: String::from("test"),
+        }
+    }
+
+    fn privilege(db: Option<&str>, collection: Option<&str>, actions: &[&str]) -> Privilege {
+        Privilege {
+            resource: PrivilegeResource {
+                db: db.map(String::from),
+                collection: collection.map(String::from),
+                ..Default::default()
+            },
+            actions: actions.iter().map(|action| action.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn read_role_covers_scan() {
+        let privileges = vec![privilege(Some("test"), Some(""), &["find", "listIndexes"])];
+        let required = super::shard_requirements(&Access::Read, &ns(), false);
+        assert!(super::missing(&privileges, &required).is_empty());
+    }
+
+    #[test]
+    fn missing_update_and_direct_shard_operations() {
+        let privileges = vec![privilege(Some("test"), Some(""), &["find", "listIndexes"])];
+        let required = super::shard_requirements(&Access::Update, &ns(), true);
+        let missing = super::missing(&privileges, &required)
+            .iter()
+            .map(Requirement::to_string)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                "update on test.test",
+                "directShardOperations on the cluster"
+            ],
+            missing
+        );
+    }
+
+    #[test]
+    fn health_sampling_requires_cluster_monitor_actions() {
+        let privileges = vec![
+            privilege(Some("test"), Some(""), &["find", "listIndexes"]),
+            Privilege {
+                resource: PrivilegeResource {
+                    cluster: true,
+                    ..Default::default()
+                },
+                actions: vec![String::from("serverStatus")],
+            },
+        ];
+        assert!(super::missing(
+            &privileges,
+            &super::shard_requirements(&Access::Read, &ns(), false)
+        )
+        .is_empty());
+        let missing = super::missing(&privileges, &super::health_requirements())
+            .iter()
+            .map(Requirement::to_string)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                "replSetGetStatus on the cluster",
+                "replSetGetConfig on the cluster"
+            ],
+            missing
+        );
+    }
+
+    #[test]
+    fn any_database_and_any_resource() {
+        let any_db = vec![privilege(Some(""), Some(""), &["find"])];
+        let required = super::router_requirements(&Access::Read, &ns());
+        assert!(super::missing(&any_db, &required).is_empty());
+
+        let root = vec![Privilege {
+            resource: PrivilegeResource {
+                any_resource: true,
+                ..Default::default()
+            },
+            actions: vec![String::from("directShardOperations")],
+        }];
+        let required = vec![Requirement {
+            resource: super::Resource::Cluster,
+            action: "directShardOperations",
+        }];
+        assert!(super::missing(&root, &required).is_empty());
+    }
+
+    #[test]
+    fn recover_requires_archive_insert_on_router() {
+        let archive_ns = mongodb::Namespace {
+            db: String::from("test"),
+            coll: String::from("archive"),
+        };
+        let privileges = vec![
+            privilege(Some("config"), Some(""), &["find"]),
+            privilege(Some("test"), Some("test"), &["insert"]),
+        ];
+        let required = super::router_requirements(
+            &Access::Recover {
+                archive_ns: Some(archive_ns),
+            },
+            &ns(),
+        );
+        assert_eq!(
+            vec![Requirement::collection("test", "archive", "insert")],
+            super::missing(&privileges, &required)
+        );
+    }
+}
@@ -0,0 +1,142 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use tracing::Instrument;
+
+/// Counters for a running scan, shared between the per-chunk tasks and a background reporter
+///
+/// each chunk is scanned on every shard except its owner, a "range" is one of those per-shard scans
+#[derive(Debug)]
+pub struct Progress {
+    started: Instant,
+    total_ranges: u64,
+    ranges_done: HashMap<String, AtomicU64>,
+    orphans_found: AtomicU64,
+}
+
+impl Progress {
+    pub fn new(shards: Vec<&String>, total_chunks: u64) -> Self {
+        let other_shards = shards.len().saturating_sub(1) as u64;
+        Progress {
+            started: Instant::now(),
+            total_ranges: total_chunks * other_shards,
+            ranges_done: HashMap::from_iter(
+                shards
+                    .into_iter()
+                    .map(|shard| (shard.to_owned(), AtomicU64::new(0))),
+            ),
+            orphans_found: AtomicU64::new(0),
+        }
+    }
+
+    pub fn range_done(&self, shard: &str) {
+        if let Some(done) = self.ranges_done.get(shard) {
+            done.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn orphan_found(&self) {
+        self.orphans_found.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn total_done(&self) -> u64 {
+        self.ranges_done
+            .values()
+            .map(|done| done.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// log a single line with ranges completed, orphans found, throughput and ETA, and per-shard counts at debug
+    pub fn log(&self) {
+        let done = self.total_done();
+        let elapsed = self.started.elapsed();
+        let orphans = self.orphans_found.load(Ordering::Relaxed);
+        let percent = if self.total_ranges > 0 {
+            done as f64 / self.total_ranges as f64 * 100.0
+        } else {
+            100.0
+        };
+        log::info!(
+            "progress: {}/{} ranges ({:.1}%), {} orphan(s) found, {:.1} ranges/s, {:.1} orphans/s, eta {}",
+            done,
+            self.total_ranges,
+            percent,
+            orphans,
+            per_second(done, elapsed),
+            per_second(orphans, elapsed),
+            eta(elapsed, done, self.total_ranges)
+                .map(|eta| format!("{}s", eta.as_secs()))
+                .unwrap_or_else(|| String::from("unknown")),
+        );
+        for (shard, done) in self.ranges_done.iter() {
+            log::debug!(
+                "progress: shard {} scanned {} range(s)",
+                shard,
+                done.load(Ordering::Relaxed)
+            );
+        }
+    }
+
+    /// log progress every interval until the returned handle is aborted
+    pub fn spawn_reporter(self: &Arc<Self>, interval: Duration) -> tokio::task::JoinHandle<()> {
+        let progress = self.clone();
+        tokio::spawn(
+            async move {
+                let mut ticker = tokio::time::interval(interval);
+                // the first tick completes immediately
+                ticker.tick().await;
+                loop {
+                    ticker.tick().await;
+                    progress.log();
+                }
+            }
+            .in_current_span(),
+        )
+    }
+}
+
+fn per_second(count: u64, elapsed: Duration) -> f64 {
+    let secs = elapsed.as_secs_f64();
+    if secs > 0.0 {
+        count as f64 / secs
+    } else {
+        0.0
+    }
+}
+
+/// estimate the time remaining assuming the remaining ranges complete at the average rate so far
+fn eta(elapsed: Duration, done: u64, total: u64) -> Option<Duration> {
+    if done == 0 {
+        return None;
+    }
+    let remaining = total.saturating_sub(done);
+    Some(elapsed.mul_f64(remaining as f64 / done as f64))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    #[test]
+    fn eta_at_average_rate() {
+        let eta = super::eta(Duration::from_secs(10), 25, 100);
+        assert_eq!(Some(Duration::from_secs(30)), eta);
+    }
+
+    #[test]
+    fn eta_unknown_before_first_range() {
+        assert_eq!(None, super::eta(Duration::from_secs(10), 0, 100));
+    }
+
+    #[test]
+    fn eta_complete() {
+        let eta = super::eta(Duration::from_secs(10), 100, 100);
+        assert_eq!(Some(Duration::ZERO), eta);
+    }
+}
@@ -0,0 +1,219 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, RwLock},
+};
+
+use clap::ValueEnum;
+use mongodb::{
+    bson,
+    options::{ReadPreference, ReadPreferenceOptions, SelectionCriteria, ServerAddress},
+};
+
+/// Which members of a shard's replica set scans read from
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum Mode {
+    Primary,
+    PrimaryPreferred,
+    Secondary,
+    SecondaryPreferred,
+    Nearest,
+}
+
+/// Read preference for the per-shard scans, independent of the router connection
+///
+/// the driver does not pin raw commands to a server, so each shard's scans are pinned to one member chosen by the
+/// driver's server selection, and their finds and getMores are all sent to that member
+#[derive(Debug)]
+pub struct ShardReadPreference {
+    mode: Mode,
+    /// tag sets tried in order, the first one matching any eligible member wins, an empty list matches every member
+    tag_sets: Vec<HashMap<String, String>>,
+}
+
+/// parse a tag set in the connection string's `readPreferenceTags` format, e.g. `dc:east,use:analytics`
+pub fn parse_tag_set(value: &str) -> Result<HashMap<String, String>, String> {
+    value
+        .split(',')
+        .filter(|tag| !tag.is_empty())
+        .map(|tag| match tag.split_once(':') {
+            Some((key, value)) => Ok((key.to_string(), value.to_string())),
+            None => Err(format!("tag {} is not of the form key:value", tag)),
+        })
+        .collect()
+}
+
+impl ShardReadPreference {
+    pub fn new(mode: Mode, tag_sets: Vec<HashMap<String, String>>) -> Self {
+        ShardReadPreference { mode, tag_sets }
+    }
+
+    /// the mode and tag sets as the driver's read preference, so server selection waits for a matching member and
+    /// falls back across preferred modes and tag sets as it would for any read
+    fn read_preference(&self) -> ReadPreference {
+        let options = ReadPreferenceOptions::builder()
+            .tag_sets((!self.tag_sets.is_empty()).then(|| self.tag_sets.clone()))
+            .build();
+        match self.mode {
+            Mode::Primary => ReadPreference::Primary,
+            Mode::PrimaryPreferred => ReadPreference::PrimaryPreferred { options },
+            Mode::Secondary => ReadPreference::Secondary { options },
+            Mode::SecondaryPreferred => ReadPreference::SecondaryPreferred { options },
+            Mode::Nearest => ReadPreference::Nearest { options },
+        }
+    }
+
+    /// select a member of the shard's replica set and return the address of the member the command was sent to
+    async fn choose(
+        &self,
+        shard: &str,
+        client: &mongodb::Client,
+    ) -> mongodb::error::Result<ServerAddress> {
+        let reply = client
+            .database("admin")
+            .run_command(
+                bson::doc! { "isMaster": 1 },
+                SelectionCriteria::ReadPreference(self.read_preference()),
+            )
+            .await?;
+
+        // the driver does not say which server it selected, so find the member naming itself in the reply among the
+        // servers it knows, recorded from a predicate during a ping
+        let known = Arc::new(Mutex::new(Vec::new()));
+        let recorder = known.clone();
+        let criteria = SelectionCriteria::Predicate(Arc::new(move |server| {
+            recorder.lock().unwrap().push(server.address().clone());
+            true
+        }));
+        client
+            .database("admin")
+            .run_command(bson::doc! { "ping": 1 }, criteria)
+            .await?;
+        let mut known = std::mem::take(&mut *known.lock().unwrap());
+        known.sort_by_key(ServerAddress::to_string);
+        known.dedup();
+
+        let me = reply
+            .get_str("me")
+            .ok()
+            .map(ServerAddress::parse)
+            .transpose()?;
+        match me {
+            Some(me) if known.contains(&me) => Ok(me),
+            // a direct connection to a remapped member knows it only by its reachable address
+            _ if known.len() == 1 => Ok(known.remove(0)),
+            _ => Err(std::io::Error::other(format!(
+                "cannot tell which member of shard {} was selected for read preference {:?} with tags {:?}",
+                shard, self.mode, self.tag_sets
+            ))
+            .into()),
+        }
+    }
+}
+
+/// A shard's reads pinned to one member, chosen again if that member can no longer be selected
+#[derive(Debug)]
+pub struct PinnedRead {
+    shard: String,
+    client: mongodb::Client,
+    preference: Arc<ShardReadPreference>,
+    address: Arc<RwLock<ServerAddress>>,
+    /// serializes re-pinning so ranges failing at once choose a new member only once
+    repin: tokio::sync::Mutex<()>,
+}
+
+impl PinnedRead {
+    pub async fn new(
+        shard: &str,
+        client: &mongodb::Client,
+        preference: Arc<ShardReadPreference>,
+    ) -> mongodb::error::Result<Self> {
+        let address = preference.choose(shard, client).await?;
+        log::info!("reading shard {} from {}", shard, address);
+        Ok(PinnedRead {
+            shard: shard.to_string(),
+            client: client.clone(),
+            preference,
+            address: Arc::new(RwLock::new(address)),
+            repin: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    pub fn address(&self) -> ServerAddress {
+        self.address.read().unwrap().clone()
+    }
+
+    /// criteria selecting only the pinned member, following it when it is chosen again
+    pub fn criteria(&self) -> SelectionCriteria {
+        let address = self.address.clone();
+        SelectionCriteria::Predicate(Arc::new(move |server| {
+            server.address() == &*address.read().unwrap()
+        }))
+    }
+
+    /// choose a member again after a read could not select the given one, unless another read already did
+    pub async fn repin(&self, failed: &ServerAddress) -> mongodb::error::Result<()> {
+        let _repin = self.repin.lock().await;
+        if self.address() != *failed {
+            return Ok(());
+        }
+        let address = self.preference.choose(&self.shard, &self.client).await?;
+        log::warn!(
+            "cannot select {} of shard {}, reading from {} instead",
+            failed,
+            self.shard,
+            address
+        );
+        *self.address.write().unwrap() = address;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use mongodb::options::ReadPreference;
+
+    use super::{Mode, ShardReadPreference};
+
+    #[test]
+    fn parse_tags() {
+        let tags = super::parse_tag_set("dc:east,use:analytics").unwrap();
+        assert_eq!(
+            HashMap::from([
+                (String::from("dc"), String::from("east")),
+                (String::from("use"), String::from("analytics"))
+            ]),
+            tags
+        );
+        assert!(super::parse_tag_set("").unwrap().is_empty());
+        assert!(super::parse_tag_set("dc").is_err());
+    }
+
+    #[test]
+    fn read_preference_by_mode() {
+        let primary = ShardReadPreference::new(Mode::Primary, vec![]);
+        assert_eq!(ReadPreference::Primary, primary.read_preference());
+        let nearest = ShardReadPreference::new(Mode::Nearest, vec![]);
+        match nearest.read_preference() {
+            ReadPreference::Nearest { options } => assert_eq!(None, options.tag_sets),
+            other => panic!("expected nearest, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn read_preference_keeps_tag_set_order() {
+        let tag_sets = vec![
+            super::parse_tag_set("use:reporting").unwrap(),
+            super::parse_tag_set("use:analytics").unwrap(),
+            super::parse_tag_set("").unwrap(),
+        ];
+        let secondary = ShardReadPreference::new(Mode::SecondaryPreferred, tag_sets.clone());
+        match secondary.read_preference() {
+            ReadPreference::SecondaryPreferred { options } => {
+                assert_eq!(Some(tag_sets), options.tag_sets)
+            }
+            other => panic!("expected secondaryPreferred, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,478 @@
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, HashSet},
+    fmt,
+};
+
+use mongodb::bson::{self, Bson};
+
+use crate::chunk::Chunk;
+
+/// rank of a value's type in the order MongoDB compares values of different types, see
+/// https://www.mongodb.com/docs/manual/reference/bson-type-comparison-order/
+fn type_rank(value: &Bson) -> u8 {
+    match value {
+        Bson::MinKey => 0,
+        Bson::Undefined | Bson::Null => 1,
+        Bson::Int32(_) | Bson::Int64(_) | Bson::Double(_) | Bson::Decimal128(_) => 2,
+        Bson::String(_) | Bson::Symbol(_) => 3,
+        Bson::Document(_) => 4,
+        Bson::Array(_) => 5,
+        Bson::Binary(_) => 6,
+        Bson::ObjectId(_) => 7,
+        Bson::Boolean(_) => 8,
+        Bson::DateTime(_) => 9,
+        Bson::Timestamp(_) => 10,
+        Bson::RegularExpression(_) => 11,
+        Bson::DbPointer(_) => 12,
+        Bson::JavaScriptCode(_) => 13,
+        Bson::JavaScriptCodeWithScope(_) => 14,
+        Bson::MaxKey => 15,
+    }
+}
+
+fn as_f64(value: &Bson) -> Option<f64> {
+    match value {
+        Bson::Int32(value) => Some(*value as f64),
+        Bson::Int64(value) => Some(*value as f64),
+        Bson::Double(value) => Some(*value),
+        _ => None,
+    }
+}
+
+/// compare two values as MongoDB orders shard key values (with the simple collation), decimals compare equal to
+/// every other number
+fn compare(a: &Bson, b: &Bson) -> Ordering {
+    let rank = type_rank(a).cmp(&type_rank(b));
+    if rank != Ordering::Equal {
+        return rank;
+    }
+    match (a, b) {
+        (Bson::Int32(_) | Bson::Int64(_), Bson::Int32(_) | Bson::Int64(_)) => {
+            let int = |value: &Bson| value.as_i64().or(value.as_i32().map(i64::from));
+            int(a).cmp(&int(b))
+        }
+        (a, b) => match (as_f64(a), as_f64(b)) {
+            // NaN sorts before every other number
+            (Some(a), Some(b)) => (!a.is_nan())
+                .cmp(&!b.is_nan())
+                .then(a.partial_cmp(&b).unwrap_or(Ordering::Equal)),
+            _ => match (a, b) {
+                (Bson::String(a), Bson::String(b)) => a.cmp(b),
+                (Bson::Document(a), Bson::Document(b)) => compare_documents(a, b),
+                (Bson::Array(a), Bson::Array(b)) => a
+                    .iter()
+                    .zip(b.iter())
+                    .map(|(a, b)| compare(a, b))
+                    .find(|ordering| *ordering != Ordering::Equal)
+                    .unwrap_or_else(|| a.len().cmp(&b.len())),
+                (Bson::Binary(a), Bson::Binary(b)) => a
+                    .bytes
+                    .len()
+                    .cmp(&b.bytes.len())
+                    .then(u8::from(a.subtype).cmp(&u8::from(b.subtype)))
+                    .then(a.bytes.cmp(&b.bytes)),
+                (Bson::ObjectId(a), Bson::ObjectId(b)) => a.bytes().cmp(&b.bytes()),
+                (Bson::Boolean(a), Bson::Boolean(b)) => a.cmp(b),
+                (Bson::DateTime(a), Bson::DateTime(b)) => a.cmp(b),
+                (Bson::Timestamp(a), Bson::Timestamp(b)) => a.cmp(b),
+                _ => Ordering::Equal,
+            },
+        },
+    }
+}
+
+/// compare two documents field by field, each field by the type of its value, then its name, then its value
+fn compare_documents(a: &bson::Document, b: &bson::Document) -> Ordering {
+    a.iter()
+        .zip(b.iter())
+        .map(|((a_field, a_value), (b_field, b_value))| {
+            type_rank(a_value)
+                .cmp(&type_rank(b_value))
+                .then(a_field.cmp(b_field))
+                .then(compare(a_value, b_value))
+        })
+        .find(|ordering| *ordering != Ordering::Equal)
+        .unwrap_or_else(|| a.len().cmp(&b.len()))
+}
+
+/// An inconsistency in a namespace's routing table, any of which makes orphan results for it meaningless
+#[derive(Debug, PartialEq)]
+pub enum Problem {
+    NoChunks,
+    /// the lowest chunk does not start at MinKey for every shard key field
+    Start(bson::Document),
+    /// the highest chunk does not end at MaxKey for every shard key field
+    End(bson::Document),
+    /// no chunk owns the range between one chunk's max and the next chunk's min
+    Gap {
+        max: bson::Document,
+        min: bson::Document,
+    },
+    /// a chunk starts before the chunk below it ends
+    Overlap {
+        max: bson::Document,
+        min: bson::Document,
+    },
+    /// a chunk's bounds do not have the shard key's fields
+    Bounds {
+        min: bson::Document,
+    },
+    UnknownShard {
+        shard: String,
+        min: bson::Document,
+    },
+    MissingVersion {
+        min: bson::Document,
+    },
+    /// a chunk's version is not above the next lower version, which is 0|0 for the lowest, as versions are only ever
+    /// assigned once and in increasing order
+    Version {
+        version: bson::Timestamp,
+        min: bson::Document,
+        /// bounds of the chunk with the next lower version, none for the lowest version
+        lower_min: Option<bson::Document>,
+    },
+    /// a chunk's version belongs to a different incarnation of the collection than the one in config.collections
+    Epoch {
+        min: bson::Document,
+    },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::NoChunks => write!(f, "no chunks"),
+            Problem::Start(min) => write!(f, "lowest chunk starts at {} instead of MinKey", min),
+            Problem::End(max) => write!(f, "highest chunk ends at {} instead of MaxKey", max),
+            Problem::Gap { max, min } => write!(f, "no chunk owns {} -> {}", max, min),
+            Problem::Overlap { max, min } => {
+                write!(
+                    f,
+                    "chunk at {} starts before the chunk below it ends at {}",
+                    min, max
+                )
+            }
+            Problem::Bounds { min } => {
+                write!(
+                    f,
+                    "chunk at {} has bounds that do not match the shard key",
+                    min
+                )
+            }
+            Problem::UnknownShard { shard, min } => {
+                write!(f, "chunk at {} is owned by unknown shard {}", min, shard)
+            }
+            Problem::MissingVersion { min } => write!(f, "chunk at {} has no version", min),
+            Problem::Version {
+                version,
+                min,
+                lower_min: Some(lower_min),
+            } => write!(
+                f,
+                "chunk at {} has version {}|{}, the same as the chunk at {}",
+                min, version.time, version.increment, lower_min
+            ),
+            Problem::Version {
+                version,
+                min,
+                lower_min: None,
+            } => write!(
+                f,
+                "chunk at {} has version {}|{}, which is never assigned",
+                min, version.time, version.increment
+            ),
+            Problem::Epoch { min } => {
+                write!(
+                    f,
+                    "chunk at {} has a version from another epoch than config.collections",
+                    min
+                )
+            }
+        }
+    }
+}
+
+/// Result of checking a namespace's routing table
+#[derive(Debug, Default)]
+pub struct Report {
+    /// number of chunks owned by each shard
+    pub shard_chunks: BTreeMap<String, usize>,
+    /// the highest chunk version, config.collections does not record it
+    pub collection_version: Option<bson::Timestamp>,
+    pub problems: Vec<Problem>,
+}
+
+impl Report {
+    pub fn total_chunks(&self) -> usize {
+        self.shard_chunks.values().sum()
+    }
+}
+
+fn all_equal(bound: &bson::Document, value: Bson) -> bool {
+    bound.values().all(|field| *field == value)
+}
+
+/// check that versions strictly increase when the chunks are ordered by version, starting above 0|0
+fn check_versions(chunks: &[Chunk], report: &mut Report) {
+    let mut versioned = chunks
+        .iter()
+        .filter_map(|chunk| chunk.lastmod.map(|version| (version, &chunk.min)))
+        .collect::<Vec<_>>();
+    versioned.sort_by_key(|(version, _)| *version);
+    let mut lower: Option<(bson::Timestamp, &bson::Document)> = None;
+    for (version, min) in versioned {
+        let lower_version = lower.map_or(
+            bson::Timestamp {
+                time: 0,
+                increment: 0,
+            },
+            |(version, _)| version,
+        );
+        if version <= lower_version {
+            report.problems.push(Problem::Version {
+                version,
+                min: min.clone(),
+                lower_min: lower.map(|(_, min)| min.clone()),
+            });
+        }
+        lower = Some((version, min));
+    }
+    report.collection_version = lower.map(|(version, _)| version);
+}
+
+/// check that the chunks of a namespace cover its whole shard key range exactly once, are owned by shards that exist,
+/// and have increasing versions of the collection's epoch in config.collections (chunks only record it before 5.0)
+pub fn check(
+    mut chunks: Vec<Chunk>,
+    shard_key: &bson::Document,
+    shards: &HashSet<String>,
+    epoch: Option<bson::oid::ObjectId>,
+) -> Report {
+    let mut report = Report::default();
+    chunks.sort_by(|a, b| compare_documents(&a.min, &b.min));
+    let (Some(first), Some(last)) = (chunks.first(), chunks.last()) else {
+        report.problems.push(Problem::NoChunks);
+        return report;
+    };
+    if !all_equal(&first.min, Bson::MinKey) {
+        report.problems.push(Problem::Start(first.min.clone()));
+    }
+    let highest = chunks
+        .iter()
+        .map(|chunk| &chunk.max)
+        .max_by(|a, b| compare_documents(a, b))
+        .unwrap_or(&last.max);
+    if !all_equal(highest, Bson::MaxKey) {
+        report.problems.push(Problem::End(highest.clone()));
+    }
+
+    check_versions(&chunks, &mut report);
+    for (i, chunk) in chunks.iter().enumerate() {
+        *report.shard_chunks.entry(chunk.shard.clone()).or_default() += 1;
+        let fields = |bound: &bson::Document| bound.keys().eq(shard_key.keys());
+        if !fields(&chunk.min) || !fields(&chunk.max) {
+            report.problems.push(Problem::Bounds {
+                min: chunk.min.clone(),
+            });
+        }
+        if !shards.contains(&chunk.shard) {
+            report.problems.push(Problem::UnknownShard {
+                shard: chunk.shard.clone(),
+                min: chunk.min.clone(),
+            });
+        }
+        if chunk.lastmod.is_none() {
+            report.problems.push(Problem::MissingVersion {
+                min: chunk.min.clone(),
+            });
+        }
+        if chunk.epoch.is_some() && epoch.is_some() && chunk.epoch != epoch {
+            report.problems.push(Problem::Epoch {
+                min: chunk.min.clone(),
+            });
+        }
+        if let Some(next) = chunks.get(i + 1) {
+            match compare_documents(&chunk.max, &next.min) {
+                Ordering::Less => report.problems.push(Problem::Gap {
+                    max: chunk.max.clone(),
+                    min: next.min.clone(),
+                }),
+                Ordering::Greater => report.problems.push(Problem::Overlap {
+                    max: chunk.max.clone(),
+                    min: next.min.clone(),
+                }),
+                Ordering::Equal => {}
+            }
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cmp::Ordering, collections::HashSet};
+
+    use mongodb::bson::{doc, oid::ObjectId, Bson, Timestamp};
+
+    use super::Problem;
+    use crate::chunk::Chunk;
+
+    fn chunk(shard: &str, min: Bson, max: Bson, version: u32) -> Chunk {
+        Chunk {
+            shard: String::from(shard),
+            min: doc! { "a": min },
+            max: doc! { "a": max },
+            lastmod: Some(Timestamp {
+                time: version,
+                increment: 0,
+            }),
+            epoch: None,
+        }
+    }
+
+    fn shards() -> HashSet<String> {
+        HashSet::from([String::from("shard01"), String::from("shard02")])
+    }
+
+    fn shard_key() -> mongodb::bson::Document {
+        doc! { "a": 1 }
+    }
+
+    #[test]
+    fn compare_across_types() {
+        let ordered = [
+            Bson::MinKey,
+            Bson::Null,
+            Bson::Double(f64::NAN),
+            Bson::Int32(-5),
+            Bson::Double(2.5),
+            Bson::Int64(3),
+            Bson::String(String::from("a")),
+            Bson::Document(doc! { "b": 1 }),
+            Bson::ObjectId(mongodb::bson::oid::ObjectId::new()),
+            Bson::Boolean(false),
+            Bson::MaxKey,
+        ];
+        for pair in ordered.windows(2) {
+            assert_eq!(
+                Ordering::Less,
+                super::compare(&pair[0], &pair[1]),
+                "{:?}",
+                pair
+            );
+        }
+        assert_eq!(
+            Ordering::Equal,
+            super::compare(&Bson::Int32(3), &Bson::Double(3.0))
+        );
+    }
+
+    #[test]
+    fn consistent_routing_table() {
+        // out of order, as config.chunks returns them
+        let chunks = vec![
+            chunk("shard02", Bson::Int32(10), Bson::MaxKey, 3),
+            chunk("shard01", Bson::MinKey, Bson::Int32(0), 1),
+            chunk("shard01", Bson::Int32(0), Bson::Int32(10), 2),
+        ];
+        let report = super::check(chunks, &shard_key(), &shards(), None);
+        assert!(report.problems.is_empty(), "{:?}", report.problems);
+        assert_eq!(
+            Some(Timestamp {
+                time: 3,
+                increment: 0
+            }),
+            report.collection_version
+        );
+        assert_eq!(3, report.total_chunks());
+        assert_eq!(Some(&2), report.shard_chunks.get("shard01"));
+    }
+
+    #[test]
+    fn gaps_overlaps_and_ends() {
+        let chunks = vec![
+            chunk("shard01", Bson::Int32(-10), Bson::Int32(0), 1),
+            chunk("shard01", Bson::Int32(5), Bson::Int32(20), 2),
+            chunk("shard02", Bson::Int32(10), Bson::Int32(30), 3),
+        ];
+        let report = super::check(chunks, &shard_key(), &shards(), None);
+        assert_eq!(
+            vec![
+                Problem::Start(doc! { "a": -10 }),
+                Problem::End(doc! { "a": 30 }),
+                Problem::Gap {
+                    max: doc! { "a": 0 },
+                    min: doc! { "a": 5 }
+                },
+                Problem::Overlap {
+                    max: doc! { "a": 20 },
+                    min: doc! { "a": 10 }
+                },
+            ],
+            report.problems
+        );
+    }
+
+    #[test]
+    fn unknown_shards_and_versions() {
+        let mut unversioned = chunk("shard01", Bson::Int32(10), Bson::MaxKey, 1);
+        unversioned.lastmod = None;
+        let chunks = vec![
+            chunk("shard01", Bson::MinKey, Bson::Int32(0), 1),
+            chunk("shard03", Bson::Int32(0), Bson::Int32(10), 1),
+            unversioned,
+        ];
+        let report = super::check(chunks, &shard_key(), &shards(), None);
+        assert_eq!(
+            vec![
+                Problem::Version {
+                    version: Timestamp {
+                        time: 1,
+                        increment: 0
+                    },
+                    min: doc! { "a": 0 },
+                    lower_min: Some(doc! { "a": Bson::MinKey })
+                },
+                Problem::UnknownShard {
+                    shard: String::from("shard03"),
+                    min: doc! { "a": 0 }
+                },
+                Problem::MissingVersion {
+                    min: doc! { "a": 10 }
+                },
+            ],
+            report.problems
+        );
+        assert_eq!(
+            vec![Problem::NoChunks],
+            super::check(Vec::new(), &shard_key(), &shards(), None).problems
+        );
+    }
+
+    #[test]
+    fn zero_versions_and_other_epochs() {
+        let epoch = ObjectId::new();
+        let mut stale = chunk("shard02", Bson::Int32(0), Bson::MaxKey, 2);
+        stale.epoch = Some(ObjectId::new());
+        let mut current = chunk("shard01", Bson::MinKey, Bson::Int32(0), 0);
+        current.epoch = Some(epoch);
+        let report = super::check(vec![current, stale], &shard_key(), &shards(), Some(epoch));
+        assert_eq!(
+            vec![
+                Problem::Version {
+                    version: Timestamp {
+                        time: 0,
+                        increment: 0
+                    },
+                    min: doc! { "a": Bson::MinKey },
+                    lower_min: None
+                },
+                Problem::Epoch {
+                    min: doc! { "a": 0 }
+                },
+            ],
+            report.problems
+        );
+    }
+}
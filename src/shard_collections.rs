@@ -0,0 +1,433 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+};
+
+use mongodb::bson::{self, Bson};
+
+/// The collection as the routing table describes it, from its config.collections document
+#[derive(Debug, Default)]
+pub struct ConfigCollection {
+    pub uuid: Option<Bson>,
+    pub default_collation: Option<bson::Document>,
+    pub dropped: bool,
+}
+
+impl ConfigCollection {
+    pub fn from_document(doc: &bson::Document) -> Self {
+        ConfigCollection {
+            uuid: doc.get("uuid").cloned(),
+            default_collation: doc
+                .get_document("defaultCollation")
+                .ok()
+                .filter(|collation| !collation.is_empty())
+                .cloned(),
+            dropped: doc.get_bool("dropped").unwrap_or(false),
+        }
+    }
+}
+
+/// The collection as a single shard has it, from listCollections and listIndexes
+#[derive(Debug, Default)]
+pub struct ShardCollection {
+    pub uuid: Option<Bson>,
+    /// options other than the collation, which is compared against the routing table's instead
+    pub options: bson::Document,
+    pub collation: Option<bson::Document>,
+    /// index definitions by name, without the fields that vary by server version
+    pub indexes: BTreeMap<String, bson::Document>,
+}
+
+impl ShardCollection {
+    pub fn new(info: &bson::Document, indexes: Vec<bson::Document>) -> Self {
+        let mut options = info.get_document("options").cloned().unwrap_or_default();
+        let collation = options
+            .remove("collation")
+            .and_then(|collation| match collation {
+                Bson::Document(collation) => Some(collation),
+                _ => None,
+            });
+        let indexes = indexes
+            .into_iter()
+            .map(|mut index| {
+                index.remove("v");
+                index.remove("ns");
+                let name = index.get_str("name").unwrap_or("<unnamed>").to_string();
+                (name, index)
+            })
+            .collect();
+        ShardCollection {
+            uuid: info
+                .get_document("info")
+                .ok()
+                .and_then(|info| info.get("uuid"))
+                .cloned(),
+            options,
+            collation,
+            indexes,
+        }
+    }
+}
+
+/// A way a shard's copy of the collection differs from the routing table or from the other shards
+#[derive(Debug, PartialEq)]
+pub enum Mismatch {
+    /// the collection is marked dropped in config.collections
+    Dropped,
+    /// the shard's collection is a different incarnation than the one the routing table describes, e.g. left over from
+    /// a drop and recreate
+    Uuid {
+        shard: String,
+        found: Option<Bson>,
+        expected: Option<Bson>,
+    },
+    Collation {
+        shard: String,
+        found: Option<bson::Document>,
+        expected: Option<bson::Document>,
+    },
+    /// options differ from those most shards have
+    Options {
+        shard: String,
+        found: bson::Document,
+        expected: bson::Document,
+    },
+    /// an index most shards have is missing
+    MissingIndex { shard: String, name: String },
+    /// an index most shards do not have
+    ExtraIndex { shard: String, name: String },
+    /// an index is defined differently than on most shards
+    Index {
+        shard: String,
+        found: bson::Document,
+        expected: bson::Document,
+    },
+}
+
+fn describe(value: &Option<impl fmt::Display>) -> String {
+    match value {
+        Some(value) => value.to_string(),
+        None => String::from("none"),
+    }
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Mismatch::Dropped => write!(f, "collection is marked dropped in config.collections"),
+            Mismatch::Uuid {
+                shard,
+                found,
+                expected,
+            } => write!(
+                f,
+                "shard {} has collection uuid {}, config.collections has {}",
+                shard,
+                describe(found),
+                describe(expected)
+            ),
+            Mismatch::Collation {
+                shard,
+                found,
+                expected,
+            } => write!(
+                f,
+                "shard {} has default collation {}, config.collections has {}",
+                shard,
+                describe(found),
+                describe(expected)
+            ),
+            Mismatch::Options {
+                shard,
+                found,
+                expected,
+            } => write!(
+                f,
+                "shard {} has collection options {}, most shards have {}",
+                shard, found, expected
+            ),
+            Mismatch::MissingIndex { shard, name } => {
+                write!(f, "shard {} is missing index {}", shard, name)
+            }
+            Mismatch::ExtraIndex { shard, name } => {
+                write!(
+                    f,
+                    "shard {} has index {} most shards do not have",
+                    shard, name
+                )
+            }
+            Mismatch::Index {
+                shard,
+                found,
+                expected,
+            } => write!(
+                f,
+                "shard {} has index {}, most shards have {}",
+                shard, found, expected
+            ),
+        }
+    }
+}
+
+/// Result of comparing every shard's copy of a collection
+#[derive(Debug, Default)]
+pub struct Report {
+    /// shards the collection does not exist on, expected of shards that never owned a chunk of it
+    pub shards_without_collection: Vec<String>,
+    pub mismatches: Vec<Mismatch>,
+}
+
+/// the value most of the given values are equal to, the earliest one on a tie
+fn consensus<'a, T: PartialEq>(values: &[&'a T]) -> Option<&'a T> {
+    let count = |value: &T| values.iter().filter(|other| **other == value).count();
+    values
+        .iter()
+        .copied()
+        .fold(None, |best: Option<&T>, value| match best {
+            Some(best) if count(best) >= count(value) => Some(best),
+            _ => Some(value),
+        })
+}
+
+/// compare every shard's copy of the collection against the routing table (uuid and default collation) and against
+/// each other (options and indexes)
+pub fn compare(
+    config: &ConfigCollection,
+    shards: &BTreeMap<String, Option<ShardCollection>>,
+) -> Report {
+    let mut report = Report::default();
+    if config.dropped {
+        report.mismatches.push(Mismatch::Dropped);
+    }
+    let present = shards
+        .iter()
+        .filter_map(|(shard, collection)| match collection {
+            Some(collection) => Some((shard, collection)),
+            None => {
+                report.shards_without_collection.push(shard.clone());
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+
+    for (shard, collection) in present.iter() {
+        if collection.uuid != config.uuid {
+            report.mismatches.push(Mismatch::Uuid {
+                shard: shard.to_string(),
+                found: collection.uuid.clone(),
+                expected: config.uuid.clone(),
+            });
+        }
+        if collection.collation != config.default_collation {
+            report.mismatches.push(Mismatch::Collation {
+                shard: shard.to_string(),
+                found: collection.collation.clone(),
+                expected: config.default_collation.clone(),
+            });
+        }
+    }
+
+    let options = present
+        .iter()
+        .map(|(_, collection)| &collection.options)
+        .collect::<Vec<_>>();
+    if let Some(expected) = consensus(&options) {
+        for (shard, collection) in present.iter() {
+            if collection.options != *expected {
+                report.mismatches.push(Mismatch::Options {
+                    shard: shard.to_string(),
+                    found: collection.options.clone(),
+                    expected: expected.clone(),
+                });
+            }
+        }
+    }
+
+    let names = present
+        .iter()
+        .flat_map(|(_, collection)| collection.indexes.keys())
+        .collect::<BTreeSet<_>>();
+    for name in names {
+        let definitions = present
+            .iter()
+            .filter_map(|(_, collection)| collection.indexes.get(name))
+            .collect::<Vec<_>>();
+        let expected = (definitions.len() * 2 > present.len())
+            .then(|| consensus(&definitions))
+            .flatten();
+        for (shard, collection) in present.iter() {
+            let shard = shard.to_string();
+            match (collection.indexes.get(name), expected) {
+                (None, Some(_)) => report.mismatches.push(Mismatch::MissingIndex {
+                    shard,
+                    name: name.clone(),
+                }),
+                (Some(_), None) => report.mismatches.push(Mismatch::ExtraIndex {
+                    shard,
+                    name: name.clone(),
+                }),
+                (Some(found), Some(expected)) if found != expected => {
+                    report.mismatches.push(Mismatch::Index {
+                        shard,
+                        found: found.clone(),
+                        expected: expected.clone(),
+                    })
+                }
+                _ => {}
+            }
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use mongodb::bson::{self, doc, Bson};
+
+    use super::{ConfigCollection, Mismatch, ShardCollection};
+
+    fn uuid(byte: u8) -> Bson {
+        Bson::Binary(bson::Binary {
+            subtype: bson::spec::BinarySubtype::Uuid,
+            bytes: vec![byte; 16],
+        })
+    }
+
+    fn collection(uuid: Bson, indexes: Vec<bson::Document>) -> ShardCollection {
+        let info = doc! {
+            "name": "test",
+            "type": "collection",
+            "options": {},
+            "info": { "readOnly": false, "uuid": uuid },
+        };
+        ShardCollection::new(&info, indexes)
+    }
+
+    fn index(name: &str, key: bson::Document) -> bson::Document {
+        doc! { "v": 2, "key": key, "name": name }
+    }
+
+    fn config() -> ConfigCollection {
+        ConfigCollection::from_document(&doc! {
+            "_id": "test.test",
+            "uuid": uuid(1),
+            "key": { "a": 1 },
+            "unique": false,
+        })
+    }
+
+    #[test]
+    fn consistent_shards() {
+        let indexes = || {
+            vec![
+                index("_id_", doc! { "_id": 1 }),
+                index("a_1", doc! { "a": 1 }),
+            ]
+        };
+        let shards = BTreeMap::from([
+            (
+                String::from("shard01"),
+                Some(collection(uuid(1), indexes())),
+            ),
+            (
+                String::from("shard02"),
+                Some(collection(uuid(1), indexes())),
+            ),
+            (String::from("shard03"), None),
+        ]);
+        let report = super::compare(&config(), &shards);
+        assert!(report.mismatches.is_empty(), "{:?}", report.mismatches);
+        assert_eq!(
+            vec![String::from("shard03")],
+            report.shards_without_collection
+        );
+    }
+
+    #[test]
+    fn recreated_collection_on_one_shard() {
+        let shards = BTreeMap::from([
+            (
+                String::from("shard01"),
+                Some(collection(
+                    uuid(1),
+                    vec![
+                        index("_id_", doc! { "_id": 1 }),
+                        index("a_1", doc! { "a": 1 }),
+                    ],
+                )),
+            ),
+            (
+                String::from("shard02"),
+                Some(collection(
+                    uuid(1),
+                    vec![
+                        index("_id_", doc! { "_id": 1 }),
+                        index("a_1", doc! { "a": 1 }),
+                    ],
+                )),
+            ),
+            (
+                String::from("shard03"),
+                Some(collection(
+                    uuid(2),
+                    vec![
+                        index("_id_", doc! { "_id": 1 }),
+                        index("a_1", doc! { "a": -1 }),
+                        index("b_1", doc! { "b": 1 }),
+                    ],
+                )),
+            ),
+        ]);
+        let report = super::compare(&config(), &shards);
+        assert_eq!(
+            vec![
+                Mismatch::Uuid {
+                    shard: String::from("shard03"),
+                    found: Some(uuid(2)),
+                    expected: Some(uuid(1)),
+                },
+                Mismatch::Index {
+                    shard: String::from("shard03"),
+                    found: doc! { "key": { "a": -1 }, "name": "a_1" },
+                    expected: doc! { "key": { "a": 1 }, "name": "a_1" },
+                },
+                Mismatch::ExtraIndex {
+                    shard: String::from("shard03"),
+                    name: String::from("b_1"),
+                },
+            ],
+            report.mismatches
+        );
+    }
+
+    #[test]
+    fn missing_index_and_collation() {
+        let mut config = config();
+        config.default_collation = Some(doc! { "locale": "fr" });
+        let shards = BTreeMap::from([
+            (
+                String::from("shard01"),
+                Some(collection(uuid(1), vec![index("a_1", doc! { "a": 1 })])),
+            ),
+            (String::from("shard02"), Some(collection(uuid(1), vec![]))),
+            (
+                String::from("shard03"),
+                Some(collection(uuid(1), vec![index("a_1", doc! { "a": 1 })])),
+            ),
+        ]);
+        let report = super::compare(&config, &shards);
+        assert_eq!(4, report.mismatches.len());
+        assert!(report.mismatches[..3]
+            .iter()
+            .all(|mismatch| matches!(mismatch, Mismatch::Collation { .. })));
+        assert_eq!(
+            Mismatch::MissingIndex {
+                shard: String::from("shard02"),
+                name: String::from("a_1"),
+            },
+            report.mismatches[3]
+        );
+    }
+}
@@ -0,0 +1,269 @@
+use mongodb::bson::{self, Bson};
+
+use crate::db;
+
+/// How a chunk's range is read on a shard
+#[derive(Debug, Clone, PartialEq)]
+pub enum RangeScan {
+    /// walk the named shard key index between the chunk bounds
+    Index(String),
+    /// scan the whole collection, filtering on the shard key
+    Collection,
+}
+
+/// What a shard has in the way of an index for range scans on the shard key
+#[derive(Debug, PartialEq)]
+enum IndexStatus {
+    /// an index whose key is exactly the shard key and which holds every document
+    Exact(String),
+    /// indexes on the shard key that cannot be hinted for a range scan, with the reason for each
+    Mismatched(Vec<String>),
+    Missing,
+}
+
+/// numeric key values of any type in the same direction are the same key, e.g. `1` and `1.0`
+fn same_key_value(a: &Bson, b: &Bson) -> bool {
+    let direction = |value: &Bson| match value {
+        Bson::Int32(value) => Some(value.signum() as f64),
+        Bson::Int64(value) => Some(value.signum() as f64),
+        Bson::Double(value) => Some(value.signum()),
+        _ => None,
+    };
+    match (direction(a), direction(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+fn same_key(a: &bson::Document, b: &bson::Document) -> bool {
+    a.len() == b.len()
+        && a.iter()
+            .zip(b.iter())
+            .all(|((a_field, a_value), (b_field, b_value))| {
+                a_field == b_field && same_key_value(a_value, b_value)
+            })
+}
+
+/// find the index a range scan on the shard key can be hinted to among the indexes listed by listIndexes, ignoring
+/// indexes that do not lead with the shard key
+fn classify(indexes: &[bson::Document], shard_key: &bson::Document) -> IndexStatus {
+    let mut mismatched = Vec::new();
+    for index in indexes {
+        let Ok(key) = index.get_document("key") else {
+            continue;
+        };
+        if key.keys().next() != shard_key.keys().next() {
+            continue;
+        }
+        let name = index.get_str("name").unwrap_or("<unnamed>");
+        let reason = if !same_key(key, shard_key) {
+            Some(format!("has key {}", key))
+        } else if index.contains_key("partialFilterExpression") {
+            Some(String::from("is partial"))
+        } else if index.get_bool("sparse").unwrap_or(false) {
+            Some(String::from("is sparse"))
+        } else if index.contains_key("collation") {
+            Some(String::from("has a non-simple collation"))
+        } else {
+            None
+        };
+        match reason {
+            None => return IndexStatus::Exact(name.to_string()),
+            Some(reason) => mismatched.push(format!("index {} {}", name, reason)),
+        }
+    }
+    if mismatched.is_empty() {
+        IndexStatus::Missing
+    } else {
+        IndexStatus::Mismatched(mismatched)
+    }
+}
+
+fn is_hashed(shard_key: &bson::Document) -> bool {
+    shard_key
+        .values()
+        .any(|value| value.as_str() == Some("hashed"))
+}
+
+/// filter matching the documents whose shard key is within [min, max), comparing in BSON order as the index does
+/// rather than with the type bracketing of query operators
+fn range_filter(
+    shard_key: &bson::Document,
+    min: &bson::Document,
+    max: &bson::Document,
+) -> bson::Document {
+    let fields = shard_key
+        .keys()
+        .map(|field| Bson::String(format!("${}", field)))
+        .collect::<Vec<_>>();
+    let bound = |bound: &bson::Document| {
+        shard_key
+            .keys()
+            .map(|field| {
+                Bson::Document(
+                    bson::doc! { "$literal": bound.get(field).cloned().unwrap_or(Bson::Null) },
+                )
+            })
+            .collect::<Vec<_>>()
+    };
+    bson::doc! {
+        "$expr": {
+            "$and": [
+                { "$gte": [&fields, bound(min)] },
+                { "$lt": [&fields, bound(max)] },
+            ]
+        }
+    }
+}
+
+impl RangeScan {
+    /// add the options selecting the documents of a chunk's range to a find command
+    pub fn apply(
+        &self,
+        command: &mut bson::Document,
+        shard_key: &bson::Document,
+        min: &bson::Document,
+        max: &bson::Document,
+    ) {
+        match self {
+            RangeScan::Index(name) => {
+                command.insert("hint", name);
+                command.insert("min", min);
+                command.insert("max", max);
+            }
+            RangeScan::Collection => {
+                command.insert("filter", range_filter(shard_key, min, max));
+            }
+        }
+    }
+}
+
+/// check a shard's indexes for one a range scan on the shard key can use, logging any problem found
+///
+/// returns a collection scan in place of a missing or mismatched index only if allowed (and the shard key is not
+/// hashed, as a chunk's bounds are then hashes a filter cannot select on), or none if the shard cannot be scanned
+pub async fn range_scan(
+    shard_name: &str,
+    client: &mongodb::Client,
+    ns: &mongodb::Namespace,
+    shard_key: &bson::Document,
+    allow_collscan: bool,
+) -> mongodb::error::Result<Option<RangeScan>> {
+    let Some(indexes) = db::list_indexes(client, ns).await? else {
+        // nothing to scan, and a find without a hint cannot fail on an index created along with the collection since
+        log::debug!("{} does not exist on shard {}", ns, shard_name);
+        return Ok(Some(RangeScan::Collection));
+    };
+    match classify(&indexes, shard_key) {
+        IndexStatus::Exact(name) => {
+            log::debug!("scanning shard {} with index {}", shard_name, name);
+            return Ok(Some(RangeScan::Index(name)));
+        }
+        IndexStatus::Mismatched(reasons) => {
+            for reason in reasons {
+                log::error!(
+                    "shard {} has no usable index on shard key {}: {}",
+                    shard_name,
+                    shard_key,
+                    reason
+                );
+            }
+        }
+        IndexStatus::Missing => log::error!(
+            "shard {} has no index on shard key {}",
+            shard_name,
+            shard_key
+        ),
+    }
+    if !allow_collscan {
+        return Ok(None);
+    }
+    if is_hashed(shard_key) {
+        log::error!(
+            "shard {} cannot fall back to a collection scan as shard key {} is hashed",
+            shard_name,
+            shard_key
+        );
+        return Ok(None);
+    }
+    log::warn!(
+        "scanning every chunk range on shard {} with a collection scan",
+        shard_name
+    );
+    Ok(Some(RangeScan::Collection))
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::{self, Bson};
+
+    use super::{IndexStatus, RangeScan};
+
+    fn index(name: &str, key: bson::Document) -> bson::Document {
+        bson::doc! { "v": 2, "name": name, "key": key }
+    }
+
+    #[test]
+    fn exact_shard_key_index() {
+        let indexes = vec![
+            index("_id_", bson::doc! { "_id": 1 }),
+            index("a_1_b_1", bson::doc! { "a": 1.0, "b": 1_i64 }),
+        ];
+        assert_eq!(
+            IndexStatus::Exact(String::from("a_1_b_1")),
+            super::classify(&indexes, &bson::doc! { "a": 1, "b": 1 })
+        );
+    }
+
+    #[test]
+    fn mismatched_and_missing_indexes() {
+        let mut partial = index("a_1", bson::doc! { "a": 1 });
+        partial.insert(
+            "partialFilterExpression",
+            bson::doc! { "a": { "$exists": true } },
+        );
+        let indexes = vec![
+            index("_id_", bson::doc! { "_id": 1 }),
+            index("a_1_b_1", bson::doc! { "a": 1, "b": 1 }),
+            partial,
+        ];
+        assert_eq!(
+            IndexStatus::Mismatched(vec![
+                String::from("index a_1_b_1 has key { \"a\": 1, \"b\": 1 }"),
+                String::from("index a_1 is partial"),
+            ]),
+            super::classify(&indexes, &bson::doc! { "a": 1 })
+        );
+        assert_eq!(
+            IndexStatus::Missing,
+            super::classify(&indexes, &bson::doc! { "b": "hashed" })
+        );
+    }
+
+    #[test]
+    fn apply_range_scan() {
+        let shard_key = bson::doc! { "a": 1, "b": 1 };
+        let min = bson::doc! { "a": 5, "b": Bson::MinKey };
+        let max = bson::doc! { "a": "$x", "b": Bson::MinKey };
+
+        let mut command = bson::doc! { "find": "test" };
+        RangeScan::Index(String::from("a_1_b_1")).apply(&mut command, &shard_key, &min, &max);
+        assert_eq!(Ok("a_1_b_1"), command.get_str("hint"));
+        assert_eq!(Ok(&min), command.get_document("min"));
+
+        let mut command = bson::doc! { "find": "test" };
+        RangeScan::Collection.apply(&mut command, &shard_key, &min, &max);
+        assert!(!command.contains_key("hint"));
+        assert_eq!(
+            &bson::doc! {
+                "$expr": {
+                    "$and": [
+                        { "$gte": [["$a", "$b"], [{ "$literal": 5 }, { "$literal": Bson::MinKey }]] },
+                        { "$lt": [["$a", "$b"], [{ "$literal": "$x" }, { "$literal": Bson::MinKey }]] },
+                    ]
+                }
+            },
+            command.get_document("filter").unwrap()
+        );
+    }
+}
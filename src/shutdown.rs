@@ -0,0 +1,49 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+/// Set once the process receives SIGINT or SIGTERM, checked by scans so they stop dispatching new work
+///
+/// a default handle is never triggered
+#[derive(Debug, Clone, Default)]
+pub struct Shutdown(Arc<AtomicBool>);
+
+impl Shutdown {
+    /// listen for SIGINT/SIGTERM in the background -- the first signal triggers the returned handle, a second one exits
+    /// immediately
+    pub fn install() -> Self {
+        let shutdown = Shutdown::default();
+        let flag = shutdown.clone();
+        tokio::spawn(async move {
+            wait_for_signal().await;
+            log::warn!("interrupted, finishing in-flight reads and printing a partial report -- interrupt again to exit immediately");
+            flag.0.store(true, Ordering::SeqCst);
+            wait_for_signal().await;
+            log::error!("interrupted again, exiting");
+            std::process::exit(130);
+        });
+        shutdown
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut terminate = signal(SignalKind::terminate()).expect("cannot listen for SIGTERM");
+    tokio::select! {
+        res = tokio::signal::ctrl_c() => res.expect("cannot listen for SIGINT"),
+        _ = terminate.recv() => {}
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() {
+    tokio::signal::ctrl_c()
+        .await
+        .expect("cannot listen for interrupts");
+}
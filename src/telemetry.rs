@@ -0,0 +1,95 @@
+use std::{fs::File, path::Path, sync::OnceLock};
+
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use tracing_subscriber::{
+    filter, fmt::format::FmtSpan, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer,
+    Registry,
+};
+
+use crate::cli::LogFormat;
+
+type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+/// provider exporting spans to an OTLP collector, flushed before the process exits
+static PROVIDER: OnceLock<SdkTracerProvider> = OnceLock::new();
+
+/// Where spans are exported, in addition to the logs written to stdout
+#[derive(Debug, Default)]
+pub struct TraceOptions<'a> {
+    /// write every span as a json line with its fields and busy/idle time when it closes
+    pub file: Option<&'a Path>,
+    /// OTLP/HTTP traces endpoint of a collector
+    pub otlp_endpoint: Option<&'a str>,
+}
+
+/// log to stdout, filtered by RUST_LOG, with `log` records from the crate and the driver forwarded as events, and
+/// export spans (but not log events) to the configured trace destinations regardless of RUST_LOG
+pub fn init(format: LogFormat, traces: TraceOptions) -> std::io::Result<()> {
+    let stdout = tracing_subscriber::fmt::layer()
+        .with_writer(std::io::stdout)
+        .with_ansi(std::io::IsTerminal::is_terminal(&std::io::stdout()));
+    let mut layers: Vec<BoxedLayer> = vec![match format {
+        LogFormat::Text => stdout.with_filter(EnvFilter::from_default_env()).boxed(),
+        LogFormat::Json => stdout
+            .json()
+            .with_span_list(true)
+            .with_filter(EnvFilter::from_default_env())
+            .boxed(),
+    }];
+
+    if let Some(path) = traces.file {
+        let file = File::create(path)?;
+        layers.push(
+            tracing_subscriber::fmt::layer()
+                .json()
+                .with_span_events(FmtSpan::CLOSE)
+                .with_writer(std::sync::Mutex::new(file))
+                .with_ansi(false)
+                .with_filter(filter::filter_fn(|metadata| metadata.is_span()))
+                .boxed(),
+        );
+    }
+
+    if let Some(endpoint) = traces.otlp_endpoint {
+        let exporter = opentelemetry_otlp::SpanExporter::builder()
+            .with_http()
+            .with_endpoint(endpoint)
+            .build()
+            .map_err(std::io::Error::other)?;
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(
+                opentelemetry_sdk::Resource::builder()
+                    .with_service_name("orphanage")
+                    .build(),
+            )
+            .build();
+        layers.push(
+            tracing_opentelemetry::layer()
+                .with_tracer(provider.tracer("orphanage"))
+                .with_filter(filter::filter_fn(|metadata| metadata.is_span()))
+                .boxed(),
+        );
+        let _ = PROVIDER.set(provider);
+    }
+
+    tracing_subscriber::registry().with(layers).init();
+    Ok(())
+}
+
+/// flush spans still buffered for the OTLP collector
+pub fn shutdown() {
+    if let Some(provider) = PROVIDER.get() {
+        if let Err(err) = provider.shutdown() {
+            log::warn!("cannot flush spans to the OTLP collector: {}", err);
+        }
+    }
+}
+
+/// flush spans, then exit with the given code
+pub fn exit(code: i32) -> ! {
+    shutdown();
+    std::process::exit(code)
+}
@@ -0,0 +1,215 @@
+use std::{collections::HashMap, future::Future, time::Duration};
+
+use mongodb::{bson, options::Acknowledgment};
+
+/// delay before the first retry of a failed write, doubled on every following attempt
+const RETRY_BACKOFF: Duration = Duration::from_millis(100);
+/// longest delay between two attempts, however many retries have been made
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(30);
+
+/// How writes are batched, acknowledged and retried
+#[derive(Debug, Clone)]
+pub struct WriteOptions {
+    pub batch_size: usize,
+    pub write_concern: Option<mongodb::options::WriteConcern>,
+    pub retries: u32,
+}
+
+/// Outcome of every write batch issued against a single shard
+#[derive(Debug, Default, Clone)]
+pub struct ShardWriteSummary {
+    pub batches: usize,
+    pub matched: u64,
+    pub modified: u64,
+    pub retried: usize,
+    pub skipped: u64,
+    pub reclassified: u64,
+    pub errors: Vec<String>,
+}
+
+/// Per-shard results of a write run
+#[derive(Debug, Default)]
+pub struct WriteSummary {
+    shard_map: HashMap<String, ShardWriteSummary>,
+    incomplete: bool,
+}
+
+impl WriteSummary {
+    /// flag the summary as partial, e.g. because the run was interrupted
+    pub fn mark_incomplete(&mut self) {
+        self.incomplete = true;
+    }
+
+    pub fn merge(&mut self, shard: &str, other: ShardWriteSummary) {
+        let summary = self.shard_map.entry(shard.to_string()).or_default();
+        summary.batches += other.batches;
+        summary.matched += other.matched;
+        summary.modified += other.modified;
+        summary.retried += other.retried;
+        summary.skipped += other.skipped;
+        summary.reclassified += other.reclassified;
+        summary.errors.extend(other.errors);
+    }
+
+    /// total documents matched across every shard
+    pub fn matched(&self) -> u64 {
+        self.shard_map.values().map(|s| s.matched).sum()
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.shard_map.values().any(|s| !s.errors.is_empty())
+    }
+
+    /// log one line per shard with batch, matched and modified counts, followed by any errors
+    pub fn log(&self) {
+        if self.incomplete {
+            log::warn!("write summary is INCOMPLETE, the run was interrupted before every chunk was processed");
+        }
+        for (shard, summary) in self.shard_map.iter() {
+            log::info!(
+                "shard {}: {} batch(es), {} matched, {} modified, {} retried, {} skipped, {} reclassified, {} error(s)",
+                shard,
+                summary.batches,
+                summary.matched,
+                summary.modified,
+                summary.retried,
+                summary.skipped,
+                summary.reclassified,
+                summary.errors.len()
+            );
+            for error in summary.errors.iter() {
+                log::error!("shard {}: {}", shard, error);
+            }
+        }
+    }
+}
+
+/// parse a write concern `w` value -- a number of nodes, "majority", or a custom tag set name
+pub fn parse_write_concern(w: &str) -> mongodb::options::WriteConcern {
+    let acknowledgment = match w.parse::<u32>() {
+        Ok(nodes) => Acknowledgment::from(nodes),
+        Err(_) => Acknowledgment::from(w.to_string()),
+    };
+    mongodb::options::WriteConcern::builder()
+        .w(acknowledgment)
+        .build()
+}
+
+/// returns true if the error is worth retrying -- network errors, server selection failures or errors the server
+/// labeled as retryable
+pub fn is_transient_error(err: &mongodb::error::Error) -> bool {
+    use mongodb::error::ErrorKind;
+    err.contains_label("RetryableWriteError")
+        || matches!(
+            err.kind.as_ref(),
+            ErrorKind::Io(_)
+                | ErrorKind::ConnectionPoolCleared { .. }
+                | ErrorKind::ServerSelection { .. }
+        )
+}
+
+/// delay before the given retry (counting from 1), doubling from RETRY_BACKOFF up to MAX_RETRY_BACKOFF
+fn backoff(attempt: u32) -> Duration {
+    RETRY_BACKOFF
+        .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+        .min(MAX_RETRY_BACKOFF)
+}
+
+/// run a write, retrying it with backoff on transient failures, and return its result along with the number of
+/// retries made
+///
+/// the write must be idempotent, or fail with an error that is not transient if repeated
+pub async fn with_retries<T, F, Fut>(
+    options: &WriteOptions,
+    description: &str,
+    mut write: F,
+) -> (mongodb::error::Result<T>, usize)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = mongodb::error::Result<T>>,
+{
+    let mut attempt = 0;
+    loop {
+        match write().await {
+            Err(err) if attempt < options.retries && is_transient_error(&err) => {
+                attempt += 1;
+                log::warn!(
+                    "retrying {}, attempt {} of {}: {}",
+                    description,
+                    attempt,
+                    options.retries,
+                    err
+                );
+                tokio::time::sleep(backoff(attempt)).await;
+            }
+            result => return (result, attempt as usize),
+        }
+    }
+}
+
+/// apply an update to a batch of ids, retrying transient failures, and record the outcome in the shard summary
+///
+/// updates are idempotent so retrying a batch that partially applied is safe
+#[tracing::instrument(skip_all, fields(ids = ids.len()))]
+pub async fn update_batch(
+    client: &mongodb::Client,
+    ns: &mongodb::Namespace,
+    ids: &[bson::oid::ObjectId],
+    update: &bson::Document,
+    options: &WriteOptions,
+    summary: &mut ShardWriteSummary,
+) {
+    let update_options = mongodb::options::UpdateOptions::builder()
+        .write_concern(options.write_concern.clone())
+        .build();
+    let collection = client
+        .database(ns.db.as_str())
+        .collection::<()>(ns.coll.as_str());
+    summary.batches += 1;
+    let (result, retried) = with_retries(options, &format!("batch of {} id(s)", ids.len()), || {
+        collection.update_many(
+            bson::doc! { "_id": { "$in": ids } },
+            update.clone(),
+            update_options.clone(),
+        )
+    })
+    .await;
+    summary.retried += retried;
+    match result {
+        Ok(result) => {
+            summary.matched += result.matched_count;
+            summary.modified += result.modified_count;
+        }
+        Err(err) => summary.errors.push(format!(
+            "batch of {} id(s) starting at {:?} failed: {}",
+            ids.len(),
+            ids.first(),
+            err
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use mongodb::options::Acknowledgment;
+
+    #[test]
+    fn parse_write_concerns() {
+        let wc = super::parse_write_concern("majority");
+        assert_eq!(Some(Acknowledgment::Majority), wc.w);
+        let wc = super::parse_write_concern("2");
+        assert_eq!(Some(Acknowledgment::Nodes(2)), wc.w);
+        let wc = super::parse_write_concern("dc");
+        assert_eq!(Some(Acknowledgment::Custom(String::from("dc"))), wc.w);
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        assert_eq!(Duration::from_millis(100), super::backoff(1));
+        assert_eq!(Duration::from_millis(400), super::backoff(3));
+        assert_eq!(super::MAX_RETRY_BACKOFF, super::backoff(40));
+        assert_eq!(super::MAX_RETRY_BACKOFF, super::backoff(u32::MAX));
+    }
+}